@@ -0,0 +1,537 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use log::info;
+use reqwest::Client;
+use rss::{Guid, Item};
+use tempfile::TempDir;
+
+use crate::cache::{self, FeedCache};
+use crate::download::{self, Feed, LocalMedia, MediaCopy, Validators};
+use crate::feed::ItemSurf;
+use crate::github::{Github, NewContent};
+use crate::images::Resizer;
+use crate::lfs::{self, Lfs};
+use crate::markdown::{self, AsMarkdown, MarkdownOptions, ReplyContext, ReplyTo, SensitiveMedia};
+use crate::mastodon::{Mastodon, Status};
+use crate::media::MediaStorage;
+use crate::media_paths::{MediaLayout, MediaPaths};
+use crate::metrics;
+use crate::privacy;
+use crate::report::{self, Published, Report};
+use crate::s3::S3;
+use crate::Settings;
+
+/// Everything needed to turn feed items into markdown and media in the repository
+pub struct Archiver<'a> {
+    settings: &'a Settings,
+    gh: Github,
+    mastodon: Mastodon,
+    media_paths: MediaPaths,
+    bucket: Option<S3>,
+    lfs: Option<Lfs>,
+    resizer: Resizer,
+    options: MarkdownOptions,
+    // Only fetched from Github when we first need it
+    gitattributes: Option<String>,
+    // The first part of each thread prepared in this run, as later parts are already in the same file
+    threads_written: HashSet<String>,
+    // Kept between polls in watch mode, as is the last GUID once we know it
+    client: Client,
+    last_guid: Option<Guid>,
+    feed_cache: FeedCache,
+    // Validators for the feed we're working through, which are only cached once we've archived everything in it
+    pending_validators: Option<Validators>,
+    report: Report,
+}
+
+/// A post rendered as markdown, with the media files it refers to, which are deleted when the post is dropped
+pub struct Post {
+    pub filename: String,
+    pub markdown_path: String,
+    pub markdown: String,
+    /// The hash recorded in the front matter, which [`Archiver::draft`] can work out again without the media
+    pub content_hash: String,
+    // Pairs of `(path, file)`, where the path is relative to the media prefix (or the bucket)
+    media: Vec<(String, PathBuf)>,
+    media_prefix: String,
+    // Where the media files are, which is removed with the post
+    _working_dir: TempDir,
+}
+
+/// A post before its media are fetched
+pub(crate) struct Draft {
+    parts: Vec<Item>,
+    id: String,
+    pub(crate) filename: String,
+    pub(crate) markdown_path: String,
+    media_prefix: String,
+    reply_to: Option<ReplyTo>,
+    pub(crate) content_hash: String,
+}
+
+impl<'a> Archiver<'a> {
+    /// An archiver for the settings, which must go together
+    pub fn new(settings: &'a Settings) -> Result<Archiver<'a>, Box<dyn Error>> {
+        settings.check()?;
+
+        let bucket = match settings.media_storage {
+            MediaStorage::S3 => Some(S3::new(
+                required(&settings.s3_endpoint)?,
+                required(&settings.s3_bucket)?,
+                &settings.s3_region,
+                required(&settings.s3_access_key)?,
+                required(&settings.s3_secret_key)?,
+            )),
+            _ => None,
+        };
+
+        let lfs = match settings.media_storage {
+            MediaStorage::Lfs => Some(Lfs::new(
+                &settings.github_repo,
+                &settings.github_token,
+                &settings.github_branch,
+            )),
+            _ => None,
+        };
+
+        let options = MarkdownOptions {
+            image_markup: settings.image_markup,
+            content_warnings: settings.content_warnings,
+            sensitive_media: settings.sensitive_media,
+            ..MarkdownOptions::new(&settings.instance)
+        };
+
+        Ok(Archiver {
+            settings,
+            gh: Github::new(
+                &settings.github_token,
+                &settings.github_repo,
+                &settings.github_branch,
+            ),
+            mastodon: Mastodon::new(&settings.instance),
+            media_paths: MediaPaths::new(settings.media_layout, &settings.media_rules)
+                .with_bundle(settings.bundle),
+            bucket,
+            lfs,
+            resizer: Resizer::new(&settings.image_widths, settings.image_format)
+                .with_details(settings.image_details),
+            options,
+            gitattributes: None,
+            threads_written: HashSet::new(),
+            client: Client::new(),
+            last_guid: None,
+            feed_cache: FeedCache::load(settings.feed_cache.as_deref())?,
+            pending_validators: None,
+            report: Report::new(&settings.feed),
+        })
+    }
+
+    // For tests, which stand in for Github and the instance with local servers
+    #[cfg(test)]
+    pub(crate) fn with_apis(self, github: &str, mastodon: &str) -> Archiver<'a> {
+        Archiver {
+            gh: self.gh.with_api(github),
+            mastodon: self.mastodon.with_api(mastodon),
+            ..self
+        }
+    }
+
+    pub(crate) fn settings(&self) -> &'a Settings {
+        self.settings
+    }
+
+    /// Start a new pass over the feed, with a new report. In watch mode, threads written in an earlier poll may have grown since.
+    pub fn begin(&mut self) {
+        self.threads_written.clear();
+        self.report = Report::new(&self.settings.feed);
+    }
+
+    /// What has happened since `begin`
+    pub fn report(&mut self) -> &mut Report {
+        &mut self.report
+    }
+
+    /// The RSS feed, as text. When `conditional`, `None` means the feed hasn't changed since we last finished it.
+    /// A feed file is read every time, and never counts as unchanged.
+    pub async fn feed(&mut self, conditional: bool) -> Result<Option<String>, Box<dyn Error>> {
+        if let Some(path) = &self.settings.feed_file {
+            return Ok(Some(download::feed_file(path).await?));
+        }
+        let url = &self.settings.feed;
+        let validators = match conditional {
+            true => self.feed_cache.get(&self.cache_key()),
+            false => None,
+        };
+        let started = Instant::now();
+        let feed = download::feed(&self.client, url, validators).await;
+        metrics::observe(
+            "mdmd_feed_fetch_seconds",
+            &[("feed", url)],
+            started.elapsed().as_secs_f64(),
+        );
+        match feed? {
+            Feed::NotModified => Ok(None),
+            Feed::Modified { text, validators } => {
+                self.pending_validators = Some(validators);
+                Ok(Some(text))
+            }
+        }
+    }
+
+    /// Everything in the feed has been archived, so next time we only need it if it has changed
+    pub(crate) fn feed_finished(&mut self) -> Result<(), Box<dyn Error>> {
+        match self.pending_validators.take() {
+            Some(validators) => {
+                let key = self.cache_key();
+                self.feed_cache.set(&key, validators)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // The feed cache entry for this feed, archived to this repository
+    fn cache_key(&self) -> String {
+        let settings = self.settings;
+        cache::key(
+            &settings.feed,
+            &settings.github_repo,
+            &settings.github_branch,
+            &settings.last_guid_git_path,
+        )
+    }
+
+    /// The last GUID archived, which is only read from Github the first time we need it
+    pub async fn last_guid(&mut self) -> Result<Guid, Box<dyn Error>> {
+        if let Some(guid) = &self.last_guid {
+            return Ok(guid.clone());
+        }
+        let guid = self
+            .gh
+            .get_last_guid(&self.settings.last_guid_git_path)
+            .await?;
+        self.last_guid = Some(guid.clone());
+        Ok(guid)
+    }
+
+    /// Record the GUID just committed as the last one archived
+    pub(crate) fn set_last_guid(&mut self, guid: &Guid) {
+        self.last_guid = Some(guid.clone());
+    }
+
+    pub(crate) fn github(&self) -> &Github {
+        &self.gh
+    }
+
+    pub(crate) fn mastodon(&self) -> &Mastodon {
+        &self.mastodon
+    }
+
+    /// Render an item, or with `--merge-threads` the whole thread it's in, fetching any media.
+    /// `None` means the item isn't to be archived.
+    pub async fn prepare(&mut self, item: &Item) -> Result<Option<Post>, Box<dyn Error>> {
+        match self.draft(item).await? {
+            Some(draft) => Ok(Some(self.complete(draft).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Work out what an item becomes, and the hash of its content, without fetching any media.
+    /// `None` means the item isn't to be archived.
+    pub(crate) async fn draft(&mut self, item: &Item) -> Result<Option<Draft>, Box<dyn Error>> {
+        let settings = self.settings;
+        let guid = item.guid().map(|g| g.value()).unwrap_or_default();
+        self.report.considered += 1;
+
+        if settings.skip_content_warnings && item.content_warning().is_some() {
+            info!("skipping {guid}: content warning");
+            self.report.skip(guid, "content warning");
+            return Ok(None);
+        }
+
+        // The post is either this item, or every part of the thread it's in, which is named after the first part:
+        let (parts, first_status) = match settings.merge_threads {
+            true => self.thread_parts(item).await?,
+            false => (vec![item.clone()], None),
+        };
+        if settings.skip_content_warnings && parts.iter().any(|p| p.content_warning().is_some()) {
+            info!("skipping {guid}: content warning in thread");
+            self.report.skip(guid, "content warning in thread");
+            return Ok(None);
+        }
+        let id = post_id(&parts[0]).to_owned();
+        if parts.len() > 1 && !self.threads_written.insert(id.clone()) {
+            info!("skipping {guid}: already written in thread {id}");
+            self.report
+                .skip(guid, &format!("already written in thread {id}"));
+            return Ok(None);
+        }
+
+        // Prepare the markdown, which for a bundle is index.md in a directory alongside its media:
+        let (filename, media_prefix) = match settings.bundle {
+            true => {
+                let slug = markdown::post_slug(parts[0].pub_date().unwrap(), &id)?;
+                let media_prefix = format!("{}/{slug}/", &settings.post_path);
+                (format!("{slug}/index.md"), media_prefix)
+            }
+            false => {
+                let filename = markdown::post_filename(parts[0].pub_date().unwrap(), &id)?;
+                (filename, settings.media_path_prefix.clone())
+            }
+        };
+        let markdown_path = format!("{}/{filename}", &settings.post_path);
+
+        // Any reply context comes from the status of the first part, which a thread has already fetched
+        let reply_to = match settings.reply_context {
+            ReplyContext::None => None,
+            context => {
+                let status = match first_status {
+                    Some(status) => Some(status),
+                    None => self.mastodon.status(&id).await?,
+                };
+                match status {
+                    Some(status) => {
+                        self.mastodon
+                            .reply_to(&status, context == ReplyContext::Quote)
+                            .await?
+                    }
+                    None => None,
+                }
+            }
+        };
+
+        // The content is hashed with media linked by their URLs alone, so the hash doesn't depend on fetching them
+        let no_media = LocalMedia::new();
+        let media_ref = |url: &str| {
+            let public_path = self.public_path(&self.media_paths.path(url, &id, &no_media));
+            no_media.media_ref(url, &public_path, !settings.drop_originals)
+        };
+        let markdown = parts.as_markdown(media_ref, &self.options, reply_to.as_ref())?;
+        let content_hash = download::digest(markdown.as_bytes());
+
+        Ok(Some(Draft {
+            parts,
+            id,
+            filename,
+            markdown_path,
+            media_prefix,
+            reply_to,
+            content_hash,
+        }))
+    }
+
+    /// Is the post already in the repository, just as it would be written now?
+    pub(crate) async fn archived(&self, draft: &Draft) -> Result<bool, Box<dyn Error>> {
+        let archived = self.gh.get_text(&draft.markdown_path).await?;
+        let hash = archived
+            .as_deref()
+            .and_then(markdown::archived_content_hash);
+        Ok(hash.as_ref() == Some(&draft.content_hash))
+    }
+
+    /// Fetch the media of a drafted post, and render it with them
+    pub(crate) async fn complete(&mut self, draft: Draft) -> Result<Post, Box<dyn Error>> {
+        let settings = self.settings;
+        let Draft {
+            parts,
+            id,
+            filename,
+            markdown_path,
+            media_prefix,
+            reply_to,
+            content_hash,
+        } = draft;
+
+        // Fetch any media, and work out where each media URL will live on the blog.
+        let skip_sensitive = settings.sensitive_media == SensitiveMedia::Exclude;
+        let working_dir = TempDir::new()?;
+        let mut media_map = parts
+            .download_all(working_dir.path(), skip_sensitive)
+            .await?;
+        if settings.strip_metadata {
+            for stripped in privacy::strip_all(&mut media_map)? {
+                let removed: Vec<String> = stripped.removed.iter().map(|m| m.to_string()).collect();
+                info!("removed {} from {}", removed.join(", "), stripped.url);
+                self.report.stripped.push(report::Stripped {
+                    filename: filename.to_owned(),
+                    media: stripped.url,
+                    removed,
+                });
+            }
+        }
+        self.resizer.resize_all(&mut media_map)?;
+
+        let media_url_to_path = |url: &str| self.media_paths.path(url, &id, &media_map);
+        let media_ref = |url: &str| {
+            let public_path = self.public_path(&media_url_to_path(url));
+            media_map.media_ref(url, &public_path, !settings.drop_originals)
+        };
+        let markdown = parts.as_markdown(media_ref, &self.options, reply_to.as_ref())?;
+        let media = media_map
+            .apply(media_url_to_path)
+            .files(!settings.drop_originals);
+
+        Ok(Post {
+            filename,
+            markdown_path,
+            markdown,
+            content_hash,
+            media,
+            media_prefix,
+            _working_dir: working_dir,
+        })
+    }
+
+    // Media in a bucket are linked from the markdown by their public URL
+    fn public_path(&self, path: &str) -> String {
+        let media_base_url = self
+            .settings
+            .media_base_url
+            .as_deref()
+            .map(|url| url.trim_end_matches('/'))
+            .unwrap_or("");
+        format!("{media_base_url}{path}")
+    }
+
+    /// Publish the media of a post, returning what needs to be committed for them.
+    /// When media go to a bucket, nothing is committed.
+    /// With LFS, the content committed for media is an LFS pointer, plus any changes to .gitattributes.
+    pub async fn publish_media(&mut self, post: &Post) -> Result<Vec<NewContent>, Box<dyn Error>> {
+        let settings = self.settings;
+
+        // LFS rules cover all media of a type under where we put media, e.g., static/**/*.jpeg
+        let lfs_root = match settings.bundle {
+            true => &settings.post_path,
+            false => &settings.media_path_prefix,
+        };
+
+        let mut new_content: Vec<NewContent> = Vec::new();
+        let mut lfs_rules: Vec<String> = Vec::new();
+        let mut uploaded = HashSet::new();
+        for (path, file) in &post.media {
+            let git_path = format!("{}{path}", post.media_prefix);
+            if let Some(bucket) = &self.bucket {
+                // Media stored by content are the same file wherever they're already in the bucket
+                let key = path.trim_start_matches('/');
+                let by_content = self.media_paths.layout() == MediaLayout::ContentHash;
+                if by_content && (!uploaded.insert(key) || bucket.exists(key).await?) {
+                    info!("skipping {key}: already in bucket");
+                    continue;
+                }
+                bucket.put(key, file).await?;
+            } else if let Some(lfs) = &self.lfs {
+                let pointer = lfs.upload(file).await?;
+                new_content.push(NewContent::text(&git_path, &pointer));
+                if let Some((_, ext)) = path.rsplit_once('.') {
+                    lfs_rules.push(lfs::rule(&format!("{lfs_root}/**/*.{ext}")));
+                }
+            } else {
+                new_content.push(NewContent::path(&git_path, file));
+            }
+        }
+
+        // ...and make sure .gitattributes sends those files through LFS.
+        if !lfs_rules.is_empty() {
+            let current = match self.gitattributes.take() {
+                Some(current) => current,
+                None => self
+                    .gh
+                    .get_text(".gitattributes")
+                    .await?
+                    .unwrap_or_default(),
+            };
+            match lfs::with_rules(&current, &lfs_rules) {
+                Some(updated) => {
+                    new_content.push(NewContent::text(".gitattributes", &updated));
+                    self.gitattributes = Some(updated);
+                }
+                None => self.gitattributes = Some(current),
+            }
+        }
+
+        Ok(new_content)
+    }
+
+    /// Commit content, leaving out media files already in the repository when they are stored by content.
+    /// Returns the URL of the commit.
+    pub async fn commit(
+        &self,
+        message: &str,
+        mut new_content: Vec<NewContent>,
+    ) -> Result<String, Box<dyn Error>> {
+        if self.media_paths.layout() == MediaLayout::ContentHash {
+            new_content = self.gh.without_existing(new_content).await?;
+        }
+        let url = self.gh.commit(message, &new_content).await?;
+
+        // Media committed to the repository count as uploaded once they are in; LFS and buckets count their own
+        let committed: u64 = new_content
+            .iter()
+            .filter_map(|content| content.file())
+            .filter_map(|file| std::fs::metadata(file).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        metrics::inc("mdmd_media_uploaded_bytes_total", &[], committed as f64);
+        Ok(url)
+    }
+
+    // The parts of the self-thread an item belongs to, oldest first, or just the item if it isn't in a thread,
+    // with the status of the first part when the instance has it
+    async fn thread_parts(
+        &self,
+        item: &Item,
+    ) -> Result<(Vec<Item>, Option<Status>), Box<dyn Error>> {
+        let Some(status) = self.mastodon.status(post_id(item)).await? else {
+            return Ok((vec![item.clone()], None));
+        };
+        let thread = self.mastodon.self_thread(status).await?;
+        match thread.len() {
+            1 => Ok((vec![item.clone()], thread.into_iter().next())),
+            _ => Ok((
+                thread.iter().map(|status| status.to_item()).collect(),
+                thread.into_iter().next(),
+            )),
+        }
+    }
+}
+
+impl Draft {
+    /// Is the post a thread of more than one part?
+    pub(crate) fn is_thread(&self) -> bool {
+        self.parts.len() > 1
+    }
+}
+
+impl Post {
+    /// The post as it goes in a report, once committed
+    pub fn published(&self, commit_url: &str) -> Published {
+        let media_bytes: u64 = self
+            .media
+            .iter()
+            .filter_map(|(_, file)| std::fs::metadata(file).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        Published {
+            filename: self.filename.clone(),
+            media: self.media.len(),
+            bytes: self.markdown.len() as u64 + media_bytes,
+            commit_url: commit_url.to_owned(),
+        }
+    }
+}
+
+// A setting which `Settings::check` makes sure is there
+fn required(setting: &Option<String>) -> Result<&str, String> {
+    setting
+        .as_deref()
+        .ok_or_else(|| "missing S3 setting".to_string())
+}
+
+// The id of a toot is the end of its URL
+fn post_id(item: &Item) -> &str {
+    item.link()
+        .and_then(|url| url.split('/').next_back())
+        .unwrap()
+}
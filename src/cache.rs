@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::download::Validators;
+
+/// The ETag and Last-Modified of each feed, as it was the last time we archived everything in it.
+/// Held in memory, and also in a JSON file if we have one, so it lasts between runs.
+/// Entries are by [`key`], as the same feed may be archived to more than one place.
+#[derive(Debug, Default)]
+pub struct FeedCache {
+    path: Option<PathBuf>,
+    feeds: BTreeMap<String, Validators>,
+}
+
+impl FeedCache {
+    /// A cache kept in the file at `path`, which doesn't need to exist yet
+    pub fn load(path: Option<&Path>) -> Result<FeedCache, Box<dyn Error>> {
+        let feeds = match path {
+            Some(path) => read(path)?,
+            None => BTreeMap::new(),
+        };
+        Ok(FeedCache {
+            path: path.map(|p| p.to_owned()),
+            feeds,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Validators> {
+        self.feeds.get(key)
+    }
+
+    /// Remember the validators for a feed, saving them to the file.
+    /// The file is re-read first, as other profiles may be sharing it.
+    pub fn set(&mut self, key: &str, validators: Validators) -> Result<(), Box<dyn Error>> {
+        self.feeds.insert(key.to_owned(), validators.clone());
+
+        if let Some(path) = &self.path {
+            let mut feeds = read(path)?;
+            feeds.insert(key.to_owned(), validators);
+            let json = serde_json::to_string_pretty(&feeds)?;
+            // Write then rename, so a crash doesn't leave half a file
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, json)?;
+            std::fs::rename(&tmp, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// The entry for a feed archived to the last GUID file at `last_guid_git_path` on a repository's branch,
+/// e.g. `https://mastodon.green/@d6y.rss d6y/blog:main:static/mastodon.green/id.txt`
+pub fn key(feed: &str, repo: &str, branch: &str, last_guid_git_path: &str) -> String {
+    format!("{feed} {repo}:{branch}:{last_guid_git_path}")
+}
+
+fn read(path: &Path) -> Result<BTreeMap<String, Validators>, Box<dyn Error>> {
+    match std::fs::read_to_string(path) {
+        Ok(json) => Ok(serde_json::from_str(&json)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(err) => Err(format!("Unable to read {}: {err}", path.display()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_cache_is_saved_and_shared() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("feeds.json");
+
+        let personal = Validators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: None,
+        };
+        let project = Validators {
+            etag: None,
+            last_modified: Some("Fri, 17 Mar 2023 18:02:11 GMT".to_string()),
+        };
+
+        // Two caches using the same file, as two profiles archiving the same feed to different places would
+        let feed = "https://mastodon.green/@d6y.rss";
+        let blog = key(feed, "d6y/blog", "main", "static/id.txt");
+        let mirror = key(feed, "d6y/mirror", "main", "static/id.txt");
+        let mut one = FeedCache::load(Some(&path)).unwrap();
+        let mut two = FeedCache::load(Some(&path)).unwrap();
+        one.set(&blog, personal.clone()).unwrap();
+        two.set(&mirror, project.clone()).unwrap();
+
+        let reloaded = FeedCache::load(Some(&path)).unwrap();
+        assert_eq!(Some(&personal), reloaded.get(&blog));
+        assert_eq!(Some(&project), reloaded.get(&mirror));
+        assert_eq!(
+            None,
+            reloaded.get(&key(feed, "d6y/blog", "drafts", "static/id.txt"))
+        );
+    }
+}
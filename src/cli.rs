@@ -0,0 +1,417 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use log::warn;
+use mdmd::{
+    ContentWarnings, Deleted, ImageMarkup, MediaLayout, MediaStorage, ReplyContext, Rule,
+    SensitiveMedia, Settings, VariantFormat,
+};
+
+/// One feed to archive: named after its profile, or the feed when there's no profile
+pub struct Run {
+    pub name: String,
+    pub args: Args,
+    pub settings: Settings,
+}
+
+// The command line, which becomes the settings for a run, or several runs with profiles
+#[derive(Parser, Debug, Clone)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// TOML file of settings, which are the same as the long argument names. Arguments and environment variables take precedence
+    #[arg(long, env = "MDMD_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Use the settings in [profiles.<PROFILE>] of the config file, as well as those at the top of the file.
+    /// Several profiles (e.g., personal,project) are run one after the other
+    #[arg(long, env = "MDMD_PROFILE", requires = "config", value_delimiter = ',')]
+    pub profile: Vec<String>,
+
+    /// RSS feed to check
+    #[arg(short, long, default_value = "http://mastodon.green/@d6y.rss")]
+    pub feed: String,
+
+    /// Read the RSS from a file, or - for stdin, rather than fetching the feed. The feed URL still names the run and its cache entry
+    #[arg(long, env = "FEED_FILE")]
+    pub feed_file: Option<PathBuf>,
+
+    /// Append a JSON report of each run, as one line, to this file, or print it with -
+    #[arg(long, env = "REPORT")]
+    pub report: Option<PathBuf>,
+
+    /// Write Prometheus metrics to this file when done, for node_exporter's textfile collector. Watch mode serves them at /metrics
+    #[arg(long, env = "METRICS_FILE")]
+    pub metrics_file: Option<PathBuf>,
+
+    /// Where to find the "last id" as a path in Git. Note git paths are rooted in "" so no leading /
+    #[arg(long, default_value = "static/mastodon.green/id.txt")]
+    pub last_guid_git_path: String,
+
+    /// Media path prefix for writing images. Note git paths are rooted in "" so no leading /
+    #[arg(short, long, default_value = "static")]
+    pub media_path_prefix: String,
+
+    /// Post path prefix for markdown files. Note git paths are rooted in "" so no leading /
+    #[arg(short, long, default_value = "content/microposts")]
+    pub post_path: String,
+
+    /// Write each post as a Hugo leaf bundle: <post-path>/<date>-toot-<id>/index.md, with its media in the same directory
+    #[arg(long, env = "BUNDLE")]
+    pub bundle: bool,
+
+    /// Instance name, which is added into the markdown header
+    #[arg(short, long, default_value = "mastodon.green")]
+    pub instance: String,
+
+    /// Github bearer token
+    #[arg(long, env = "GITHUB_TOKEN", hide_env_values = true)]
+    pub github_token: String,
+
+    /// Github repository in the form "user/repo"
+    #[arg(long, env = "GITHUB_REPO")]
+    pub github_repo: String,
+
+    /// Github repository branch
+    #[arg(long, env = "GITHUB_BRANCH", default_value = "main")]
+    pub github_branch: String,
+
+    /// Number of posts to read per run
+    #[arg(long, short, env = "NUM_POSTS", default_value = "1")]
+    pub num_posts: usize,
+
+    /// How to name media files. The content-hash layout stores each distinct file once, however many posts use it
+    #[arg(long, env = "MEDIA_LAYOUT", value_enum, default_value = "url")]
+    pub media_layout: MediaLayout,
+
+    /// Deprecated: the same as --media-layout content-hash
+    #[arg(long, env = "DEDUPE_MEDIA", hide = true)]
+    pub dedupe_media: bool,
+
+    /// Rewrite media URLs starting with a prefix, written as FROM=TO (e.g., https://cdn.example.org/=/example.org/). Can be repeated
+    #[arg(long = "media-prefix", env = "MEDIA_PREFIX", value_parser = Rule::prefix)]
+    pub media_prefixes: Vec<Rule>,
+
+    /// Rewrite media URLs matching a regex, written as PATTERN=REPLACEMENT (e.g., ^https://[^/]+/system/(.*)$=/system/$1). Tried after prefixes. Can be repeated
+    #[arg(long = "media-regex", env = "MEDIA_REGEX", value_parser = Rule::regex)]
+    pub media_regexes: Vec<Rule>,
+
+    /// Widths of resized images to produce, e.g. 480,960,1600. No resizing happens unless this is set
+    #[arg(long, env = "IMAGE_WIDTHS", value_delimiter = ',')]
+    pub image_widths: Vec<u32>,
+
+    /// File format for resized images
+    #[arg(long, env = "IMAGE_FORMAT", value_enum, default_value = "original")]
+    pub image_format: VariantFormat,
+
+    /// Only commit the resized images, not the original, when an image has been resized
+    #[arg(long, env = "DROP_ORIGINALS")]
+    pub drop_originals: bool,
+
+    /// Measure images, adding width, height, and blurhash to the markdown and an `images:` list to the front matter
+    #[arg(long, env = "IMAGE_DETAILS")]
+    pub image_details: bool,
+
+    /// Remove EXIF (including GPS) and XMP metadata from JPEG, PNG, and WebP images before committing them
+    #[arg(long, env = "STRIP_METADATA")]
+    pub strip_metadata: bool,
+
+    /// How to write images into the markdown
+    #[arg(long, env = "IMAGE_MARKUP", value_enum, default_value = "markdown")]
+    pub image_markup: ImageMarkup,
+
+    /// How to show posts with a content warning
+    #[arg(long, env = "CONTENT_WARNINGS", value_enum, default_value = "details")]
+    pub content_warnings: ContentWarnings,
+
+    /// Don't archive posts that have a content warning
+    #[arg(long, env = "SKIP_CONTENT_WARNINGS")]
+    pub skip_content_warnings: bool,
+
+    /// How to show media marked as sensitive: behind a click to reveal, inline, or not at all
+    #[arg(long, env = "SENSITIVE_MEDIA", value_enum, default_value = "reveal")]
+    pub sensitive_media: SensitiveMedia,
+
+    /// Write a thread of replies to yourself as a single post, rewriting it when the thread grows. Uses the instance's API
+    #[arg(long, env = "MERGE_THREADS")]
+    pub merge_threads: bool,
+
+    /// For replies, add the URL and account of the post replied to into the front matter, optionally with a quote from it. Uses the instance's API
+    #[arg(long, env = "REPLY_CONTEXT", value_enum, default_value = "none")]
+    pub reply_context: ReplyContext,
+
+    /// JSON file to keep each feed's ETag and Last-Modified in, so a sync can stop early when the feed hasn't changed
+    #[arg(long, env = "FEED_CACHE")]
+    pub feed_cache: Option<PathBuf>,
+
+    /// Secret shared with webhooks and WebSub hubs, which sign their requests with it. In watch mode, a signed request syncs straight away
+    #[arg(long, env = "WEBHOOK_SECRET", hide_env_values = true)]
+    pub webhook_secret: Option<String>,
+
+    /// Where to publish media files: committed to Github (optionally via Git LFS), or uploaded to an S3-compatible bucket
+    #[arg(long, env = "MEDIA_STORAGE", value_enum, default_value = "git")]
+    pub media_storage: MediaStorage,
+
+    /// S3 endpoint, e.g. https://s3.eu-west-2.amazonaws.com or http://localhost:9000 for MinIO
+    #[arg(long, env = "S3_ENDPOINT", required_if_eq("media_storage", "s3"))]
+    pub s3_endpoint: Option<String>,
+
+    /// S3 bucket name
+    #[arg(long, env = "S3_BUCKET", required_if_eq("media_storage", "s3"))]
+    pub s3_bucket: Option<String>,
+
+    /// S3 region
+    #[arg(long, env = "S3_REGION", default_value = "us-east-1")]
+    pub s3_region: String,
+
+    /// S3 access key
+    #[arg(long, env = "AWS_ACCESS_KEY_ID", required_if_eq("media_storage", "s3"))]
+    pub s3_access_key: Option<String>,
+
+    /// S3 secret key
+    #[arg(
+        long,
+        env = "AWS_SECRET_ACCESS_KEY",
+        hide_env_values = true,
+        required_if_eq("media_storage", "s3")
+    )]
+    pub s3_secret_key: Option<String>,
+
+    /// Public URL of the bucket, used for media links in the markdown, e.g. https://media.example.org
+    #[arg(long, env = "MEDIA_BASE_URL", required_if_eq("media_storage", "s3"))]
+    pub media_base_url: Option<String>,
+}
+
+impl Args {
+    /// The settings for the library, which checks they go together
+    pub fn settings(&self) -> Result<Settings, Box<dyn Error>> {
+        let mut builder = Settings::builder(&self.github_token, &self.github_repo)
+            .feed(&self.feed)
+            .last_guid_git_path(&self.last_guid_git_path)
+            .media_path_prefix(&self.media_path_prefix)
+            .post_path(&self.post_path)
+            .bundle(self.bundle)
+            .instance(&self.instance)
+            .github_branch(&self.github_branch)
+            .num_posts(self.num_posts)
+            .media_layout(self.media_layout())
+            .image_widths(&self.image_widths)
+            .image_format(self.image_format)
+            .drop_originals(self.drop_originals)
+            .image_details(self.image_details)
+            .strip_metadata(self.strip_metadata)
+            .image_markup(self.image_markup)
+            .content_warnings(self.content_warnings)
+            .skip_content_warnings(self.skip_content_warnings)
+            .sensitive_media(self.sensitive_media)
+            .merge_threads(self.merge_threads)
+            .reply_context(self.reply_context)
+            .s3_region(&self.s3_region);
+        for rule in self.media_prefixes.iter().chain(&self.media_regexes) {
+            builder = builder.media_rule(rule.clone());
+        }
+        if let Some(path) = &self.feed_file {
+            builder = builder.feed_file(path);
+        }
+        if let Some(path) = &self.report {
+            builder = builder.report(path);
+        }
+        if let Some(path) = &self.feed_cache {
+            builder = builder.feed_cache(path);
+        }
+        if let (Some(endpoint), Some(bucket), Some(access_key), Some(secret_key), Some(base_url)) = (
+            &self.s3_endpoint,
+            &self.s3_bucket,
+            &self.s3_access_key,
+            &self.s3_secret_key,
+            &self.media_base_url,
+        ) {
+            builder = builder.s3(endpoint, bucket, access_key, secret_key, base_url);
+        }
+        builder.media_storage(self.media_storage).build()
+    }
+
+    // --dedupe-media came before the media layouts, and still means the content-hash layout
+    fn media_layout(&self) -> MediaLayout {
+        match self.dedupe_media {
+            true => {
+                warn!("--dedupe-media is deprecated: use --media-layout content-hash");
+                MediaLayout::ContentHash
+            }
+            false => self.media_layout,
+        }
+    }
+
+    /// Does the command print its results, which a report on stdout would be mixed up with?
+    pub fn prints(&self) -> bool {
+        matches!(
+            self.command,
+            Some(Command::Status) | Some(Command::Render { .. }) | Some(Command::Reconcile { .. })
+        )
+    }
+
+    /// Is the feed read from stdin?
+    pub fn reads_stdin(&self) -> bool {
+        self.feed_file.as_deref() == Some(Path::new("-"))
+    }
+
+    /// Do both archive to the same last GUID file, in the same repository and branch?
+    pub fn same_archive(&self, other: &Args) -> bool {
+        self.github_repo == other.github_repo
+            && self.github_branch == other.github_branch
+            && self.last_guid_git_path == other.last_guid_git_path
+    }
+
+    /// What to run for each feed, which is `None` for watch as it keeps running syncs
+    pub fn library_command(&self) -> Option<mdmd::Command> {
+        let command = match self.command.clone().unwrap_or(Command::Sync) {
+            Command::Sync => mdmd::Command::Sync,
+            Command::Status => mdmd::Command::Status,
+            Command::Render { item } => mdmd::Command::Render { item },
+            Command::SetCursor { guid, force } => mdmd::Command::SetCursor { guid, force },
+            Command::Update => mdmd::Command::Update,
+            Command::Watch { .. } => return None,
+            Command::Reconcile {
+                window_days,
+                deleted,
+                confirm,
+            } => mdmd::Command::Reconcile {
+                window_days,
+                deleted,
+                confirm,
+            },
+        };
+        Some(command)
+    }
+}
+
+/// Without a command, new posts are archived, as with `sync`
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Archive new posts from the feed
+    Sync,
+    /// Show the last GUID archived, how many posts in the feed are waiting to be archived, and the last commit
+    Status,
+    /// Print the markdown for one post, without committing anything
+    Render {
+        /// The GUID or URL of the toot
+        item: String,
+    },
+    /// Commit a new last GUID, so the next sync archives the posts after it
+    SetCursor {
+        /// The GUID to record as archived
+        guid: String,
+
+        /// Set the GUID even if it isn't in the feed, and doesn't look like a toot on the instance
+        #[arg(long)]
+        force: bool,
+    },
+    /// Re-render posts we've already archived, committing any that have changed (e.g., an edited toot)
+    Update,
+    /// Keep running, archiving new posts every so often, until stopped with SIGTERM or Ctrl-C
+    Watch {
+        /// Seconds between polls of the feed, or 0 to only sync when a webhook asks
+        #[arg(long, default_value = "300")]
+        interval: u64,
+
+        /// Up to this many seconds are added to each interval at random, so several instances don't poll in step
+        #[arg(long, default_value = "30")]
+        jitter: u64,
+
+        /// Address to listen on, e.g. 0.0.0.0:8080, for a health check at /health, and webhooks when there's a --webhook-secret
+        #[arg(long, alias = "health")]
+        listen: Option<SocketAddr>,
+
+        /// Seconds to wait after a webhook before syncing, so a burst of them leads to one sync
+        #[arg(long, default_value = "5")]
+        debounce: u64,
+    },
+    /// Find archived posts whose toots have been deleted, and remove them from the blog. Only reports what it would do, unless --confirm is given
+    Reconcile {
+        /// How many days back to check posts, by the date in their file name
+        #[arg(long, default_value = "30")]
+        window_days: i64,
+
+        /// What to do with a post whose toot has gone
+        #[arg(long, value_enum, default_value = "draft")]
+        deleted: Deleted,
+
+        /// Commit the changes, rather than just listing them
+        #[arg(long)]
+        confirm: bool,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    // Leaving out environment variables, which may be set where the tests run
+    fn parse(cli: &[&str]) -> Args {
+        let matches = Args::command()
+            .mut_args(|arg| arg.env(None))
+            .try_get_matches_from(
+                ["mdmd", "--github-token=token", "--github-repo=d6y/blog"]
+                    .iter()
+                    .chain(cli),
+            )
+            .unwrap();
+        Args::from_arg_matches(&matches).unwrap()
+    }
+
+    #[test]
+    fn test_defaults_match_the_library() {
+        let args = parse(&[]);
+        let from_cli = args.settings().unwrap();
+        let from_builder = Settings::builder("token", "d6y/blog").build().unwrap();
+        assert_eq!(format!("{from_builder:?}"), format!("{from_cli:?}"));
+    }
+
+    #[test]
+    fn test_bundle_only_conflicts_with_s3() {
+        let git = parse(&["--bundle", "--media-storage=git"]);
+        assert!(git.settings().is_ok());
+        let lfs = parse(&["--bundle", "--media-storage=lfs"]);
+        assert!(lfs.settings().is_ok());
+
+        let s3 = parse(&[
+            "--bundle",
+            "--media-storage=s3",
+            "--s3-endpoint=http://localhost:9000",
+            "--s3-bucket=media",
+            "--s3-access-key=key",
+            "--s3-secret-key=secret",
+            "--media-base-url=https://media.example.org",
+        ]);
+        assert!(s3.settings().is_err());
+    }
+
+    #[test]
+    fn test_dedupe_media_is_content_hash() {
+        let args = parse(&["--dedupe-media"]);
+        assert_eq!(
+            MediaLayout::ContentHash,
+            args.settings().unwrap().media_layout()
+        );
+    }
+
+    #[test]
+    fn test_image_widths_are_sorted() {
+        let args = parse(&["--image-widths=1600,480,960,480"]);
+        assert_eq!(&[480, 960, 1600], args.settings().unwrap().image_widths());
+    }
+
+    #[test]
+    fn test_same_archive() {
+        let personal = parse(&["--feed=https://mastodon.green/@d6y.rss"]);
+        let project = parse(&["--feed=https://fosstodon.org/@project.rss"]);
+        assert!(personal.same_archive(&project));
+
+        let elsewhere = parse(&["--last-guid-git-path=static/fosstodon.org/id.txt"]);
+        assert!(!personal.same_archive(&elsewhere));
+    }
+}
@@ -0,0 +1,251 @@
+use std::error::Error;
+use std::ffi::OsString;
+use std::sync::LazyLock;
+
+use clap::parser::ValueSource;
+use clap::Command;
+use regex::Regex;
+use toml::{Table, Value};
+
+/// A setting from a config file: the long argument name, and its values
+pub type Setting = (String, Vec<String>);
+
+/// Settings from a config file, which become command line arguments.
+///
+/// Top level keys apply to every profile, and `[profiles.<name>]` tables add to or replace them:
+///
+/// ```toml
+/// github_branch = "main"
+///
+/// [profiles.d6y]
+/// feed = "https://mastodon.green/@d6y.rss"
+/// github_token = "${GITHUB_TOKEN}"
+/// ```
+///
+/// Keys are argument names, with either `_` or `-`. Strings can refer to environment variables as `${NAME}`,
+/// which are looked up with `env`.
+pub fn profile_args(
+    config: &str,
+    profile: Option<&str>,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<Vec<Setting>, Box<dyn Error>> {
+    let config: Table = config.parse()?;
+
+    let mut settings: Vec<Setting> = Vec::new();
+    let mut set = |key: &str, value: &Value| -> Result<(), Box<dyn Error>> {
+        let name = key.replace('_', "-");
+        let values = values(key, value, env)?;
+        settings.retain(|(existing, _)| existing != &name);
+        settings.push((name, values));
+        Ok(())
+    };
+
+    for (key, value) in &config {
+        if key != "profiles" {
+            set(key, value)?;
+        }
+    }
+
+    if let Some(name) = profile {
+        let profile = config
+            .get("profiles")
+            .and_then(|profiles| profiles.get(name))
+            .and_then(|profile| profile.as_table())
+            .ok_or_else(|| format!("No profile called {name} in the config file"))?;
+        for (key, value) in profile {
+            set(key, value)?;
+        }
+    }
+
+    Ok(settings)
+}
+
+/// Command line arguments with settings from a config file added in front of them.
+/// Anything given on the command line, or in an environment variable, wins over the file.
+pub fn with_profile(
+    command: &Command,
+    cli: Vec<OsString>,
+    settings: &[Setting],
+) -> Result<Vec<OsString>, Box<dyn Error>> {
+    let mut args: Vec<OsString> = cli.iter().take(1).cloned().collect();
+
+    // Clap knows where each argument came from, however it was written (e.g., -n5, or among combined short flags)
+    let given = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(cli.iter())?;
+
+    for (name, values) in settings {
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(name.as_str()))
+            .ok_or_else(|| format!("Unknown setting in the config file: {name}"))?;
+
+        let source = given.value_source(arg.get_id().as_str());
+        if matches!(
+            source,
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+
+        // Flags have no value: they are either there or not
+        let is_flag = !arg.get_action().takes_values();
+        for value in values {
+            match is_flag {
+                true if value == "true" => args.push(format!("--{name}").into()),
+                true => {}
+                false => args.push(format!("--{name}={value}").into()),
+            }
+        }
+    }
+
+    args.extend(cli.into_iter().skip(1));
+    Ok(args)
+}
+
+// The values of a setting as strings, with any environment variables filled in
+fn values(
+    key: &str,
+    value: &Value,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    match value {
+        Value::String(s) => Ok(vec![interpolate(s, env)?]),
+        Value::Integer(i) => Ok(vec![i.to_string()]),
+        Value::Float(f) => Ok(vec![f.to_string()]),
+        Value::Boolean(b) => Ok(vec![b.to_string()]),
+        Value::Array(values) => {
+            let mut all = Vec::new();
+            for value in values {
+                all.append(&mut self::values(key, value, env)?);
+            }
+            Ok(all)
+        }
+        _ => Err(format!("Unexpected value for {key} in the config file: {value}").into()),
+    }
+}
+
+// Replace ${NAME} with the value of the environment variable NAME
+fn interpolate(text: &str, env: &dyn Fn(&str) -> Option<String>) -> Result<String, Box<dyn Error>> {
+    static VAR: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap());
+    let mut missing = None;
+    let result = VAR.replace_all(text, |captures: &regex::Captures| {
+        env(&captures[1]).unwrap_or_else(|| {
+            missing = Some(captures[1].to_owned());
+            String::new()
+        })
+    });
+    match missing {
+        Some(name) => Err(format!("Environment variable {name} is not set").into()),
+        None => Ok(result.into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, ArgAction};
+    use pretty_assertions::assert_eq;
+
+    const CONFIG: &str = r#"
+instance = "mastodon.green"
+github_branch = "main"
+
+[profiles.d6y]
+feed = "https://mastodon.green/@d6y.rss"
+github-token = "token-${MDMD_TEST_TOKEN}"
+
+[profiles.project]
+instance = "fosstodon.org"
+image_widths = [480, 960]
+bundle = true
+"#;
+
+    fn setting(name: &str, values: &[&str]) -> Setting {
+        (
+            name.to_string(),
+            values.iter().map(|v| v.to_string()).collect(),
+        )
+    }
+
+    fn env(name: &str) -> Option<String> {
+        (name == "MDMD_TEST_TOKEN").then(|| "abc".to_string())
+    }
+
+    #[test]
+    fn test_profile_args() {
+        assert_eq!(
+            vec![
+                setting("github-branch", &["main"]),
+                setting("instance", &["mastodon.green"]),
+                setting("feed", &["https://mastodon.green/@d6y.rss"]),
+                setting("github-token", &["token-abc"]),
+            ],
+            profile_args(CONFIG, Some("d6y"), &env).unwrap()
+        );
+
+        assert_eq!(
+            vec![
+                setting("github-branch", &["main"]),
+                setting("bundle", &["true"]),
+                setting("image-widths", &["480", "960"]),
+                setting("instance", &["fosstodon.org"]),
+            ],
+            profile_args(CONFIG, Some("project"), &env).unwrap()
+        );
+
+        assert!(profile_args(CONFIG, Some("nobody"), &env).is_err());
+        assert!(profile_args("token = \"${MDMD_TEST_UNSET}\"", None, &env).is_err());
+    }
+
+    #[test]
+    fn test_command_line_wins() {
+        let command = Command::new("mdmd")
+            .arg(Arg::new("instance").long("instance").short('i'))
+            .arg(Arg::new("feed").long("feed"))
+            .arg(
+                Arg::new("bundle")
+                    .long("bundle")
+                    .short('b')
+                    .action(ArgAction::SetTrue),
+            )
+            .arg(Arg::new("num-posts").long("num-posts").short('n'))
+            .arg(Arg::new("node").long("node"));
+        let settings = vec![
+            setting("instance", &["mastodon.green"]),
+            setting("feed", &["https://mastodon.green/@d6y.rss"]),
+            setting("bundle", &["true"]),
+        ];
+        let cli: Vec<OsString> = ["mdmd", "-i", "fosstodon.org"]
+            .iter()
+            .map(OsString::from)
+            .collect();
+
+        let args = with_profile(&command, cli, &settings).unwrap();
+        assert_eq!(
+            vec![
+                "mdmd",
+                "--feed=https://mastodon.green/@d6y.rss",
+                "--bundle",
+                "-i",
+                "fosstodon.org"
+            ],
+            args
+        );
+
+        // -n5 is --num-posts, not --node, and -bn5 includes --bundle
+        let settings = vec![
+            setting("num-posts", &["10"]),
+            setting("node", &["a"]),
+            setting("bundle", &["true"]),
+        ];
+        let cli: Vec<OsString> = ["mdmd", "-bn5"].iter().map(OsString::from).collect();
+        let args = with_profile(&command, cli, &settings).unwrap();
+        assert_eq!(vec!["mdmd", "--node=a", "-bn5"], args);
+
+        let unknown = vec![setting("colour", &["blue"])];
+        assert!(with_profile(&command, vec![], &unknown).is_err());
+    }
+}
@@ -3,25 +3,98 @@ use std::error::Error;
 use std::path::{Path, PathBuf};
 
 use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-use crate::feed::ItemSurf;
-use reqwest::Client;
+use crate::feed::{ItemSurf, MediaSurf};
+use crate::images::ImageDetails;
+use crate::markdown::MediaRef;
+use crate::media_paths;
+use crate::metrics;
+use reqwest::{Client, StatusCode};
 use rss::Item;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-// Download a URL content as text
-pub async fn feed(url: &str) -> Result<String, Box<dyn Error>> {
-    let client = Client::new();
-    let response = client.get(url).send().await?;
-    let value = response.text().await?;
-    Ok(value)
+/// Download a URL content as text.
+/// With `validators` from an earlier download, the request is conditional, and the server may say nothing has changed.
+pub async fn feed(
+    client: &Client,
+    url: &str,
+    validators: Option<&Validators>,
+) -> Result<Feed, Box<dyn Error>> {
+    let mut request = client.get(url);
+    if let Some(etag) = validators.and_then(|v| v.etag.as_ref()) {
+        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = validators.and_then(|v| v.last_modified.as_ref()) {
+        request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+    }
+
+    let response = request.send().await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(Feed::NotModified);
+    }
+
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_owned())
+    };
+    let validators = Validators {
+        etag: header(reqwest::header::ETAG),
+        last_modified: header(reqwest::header::LAST_MODIFIED),
+    };
+    let text = response.text().await?;
+    Ok(Feed::Modified { text, validators })
 }
 
-// This is a kind of map from URLs to media and the correponding file...
+/// Read a feed saved to disk, or from stdin when the path is `-`
+pub async fn feed_file(path: &Path) -> Result<String, Box<dyn Error>> {
+    if path == Path::new("-") {
+        let mut text = String::new();
+        tokio::io::stdin().read_to_string(&mut text).await?;
+        return Ok(text);
+    }
+    tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| format!("Unable to read {}: {e}", path.display()).into())
+}
+
+/// The result of downloading a feed
 #[derive(Debug)]
+pub enum Feed {
+    Modified {
+        text: String,
+        validators: Validators,
+    },
+    NotModified,
+}
+
+/// What a server told us about a version of a feed, so we can ask if it has changed since
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// This is a kind of map from URLs to media and the correponding file...
+#[derive(Debug, Default)]
 pub struct LocalMedia {
     urls: Vec<String>,
     local_files: Vec<PathBuf>,
+    digests: Vec<String>, // SHA-256 of each file, as hex
+    variants: Vec<Vec<Variant>>,
+    details: Vec<Option<ImageDetails>>,
+}
+
+/// A resized copy of a downloaded image
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub width: u32,
+    pub height: u32,
+    pub local_file: PathBuf,
 }
 
 /// ... which we can iterate over of pairs of `(url, path)`
@@ -35,57 +108,306 @@ impl<'a> IntoIterator for &'a LocalMedia {
 }
 
 impl LocalMedia {
-    fn new() -> LocalMedia {
-        LocalMedia {
-            urls: vec![],
-            local_files: vec![],
-        }
+    /// No media yet
+    pub fn new() -> LocalMedia {
+        LocalMedia::default()
     }
 
-    fn push(&mut self, url: &str, local_file: &Path) {
+    /// Add a media URL, downloaded to `local_file`, with the SHA-256 of its content
+    pub fn push(&mut self, url: &str, local_file: &Path, digest: &str) {
         self.urls.push(url.to_owned());
         self.local_files.push(local_file.to_owned());
+        self.digests.push(digest.to_owned());
+        self.variants.push(vec![]);
+        self.details.push(None);
+    }
+
+    /// The number of media
+    pub fn len(&self) -> usize {
+        self.urls.len()
+    }
+
+    /// Are there no media?
+    pub fn is_empty(&self) -> bool {
+        self.urls.is_empty()
+    }
+
+    /// The position of a URL in the order the media were attached to the post
+    pub fn index_of(&self, url: &str) -> Option<usize> {
+        self.urls.iter().position(|u| u == url)
+    }
+
+    /// The downloaded files, in the order the media were attached
+    pub fn local_files(&self) -> &[PathBuf] {
+        &self.local_files
+    }
+
+    /// Replace the SHA-256 of a file, e.g., after its metadata has been stripped
+    pub fn set_digest(&mut self, index: usize, digest: &str) {
+        self.digests[index] = digest.to_owned();
     }
 
-    // Apply a function, `f`, to the URLs.
-    // This is useful for converting references to files on a Mastodon instance to "local" URLs in our markdown (blog)
+    /// Record the dimensions and blurhash of an image
+    pub fn set_details(&mut self, index: usize, details: Option<ImageDetails>) {
+        self.details[index] = details;
+    }
+
+    /// Record the resized copies of an image
+    pub fn set_variants(&mut self, index: usize, variants: Vec<Variant>) {
+        self.variants[index] = variants;
+    }
+
+    /// Apply a function, `f`, to the URLs.
+    /// This is useful for converting references to files on a Mastodon instance to "local" URLs in our markdown (blog)
     pub fn apply<F: Fn(&str) -> String>(&self, f: F) -> LocalMedia {
         let new_urls = self.urls.iter().map(|u| f(u)).collect();
         LocalMedia {
             urls: new_urls,
             local_files: self.local_files.to_owned(),
+            digests: self.digests.to_owned(),
+            variants: self.variants.to_owned(),
+            details: self.details.to_owned(),
+        }
+    }
+
+    /// All the files to publish, as pairs of `(url, path)`, including any resized variants.
+    /// When `originals` is false, an image with variants is only published as variants.
+    pub fn files(&self, originals: bool) -> Vec<(String, PathBuf)> {
+        let mut files = Vec::new();
+        for ((url, local_file), variants) in self.into_iter().zip(self.variants.iter()) {
+            if originals || variants.is_empty() {
+                files.push((url.to_owned(), local_file.to_owned()));
+            }
+            for variant in variants {
+                files.push((variant_url(url, variant), variant.local_file.to_owned()));
+            }
+        }
+        files
+    }
+
+    /// Describe where a media URL ends up on the blog, given the `path` the URL maps to.
+    /// Without `originals`, the largest variant stands in for the original.
+    pub fn media_ref(&self, url: &str, path: &str, originals: bool) -> MediaRef {
+        let index = self.index_of(url);
+
+        let resized = index.map(|index| &self.variants[index][..]).unwrap_or(&[]);
+        let variants: Vec<(u32, String)> = resized
+            .iter()
+            .map(|v| (v.width, variant_url(path, v)))
+            .collect();
+
+        let details = index.and_then(|index| self.details[index].to_owned());
+
+        // The details describe the file we link to, so they have the size of any variant standing in for the original
+        match resized.last() {
+            Some(largest) if !originals => MediaRef {
+                path: variant_url(path, largest),
+                variants,
+                details: details.map(|details| ImageDetails {
+                    width: largest.width,
+                    height: largest.height,
+                    ..details
+                }),
+            },
+            _ => MediaRef {
+                path: path.to_owned(),
+                variants,
+                details,
+            },
+        }
+    }
+
+    /// The location of a media URL when media are stored by content: `/media/<sha256>.<ext>`.
+    /// The same file attached to several posts ends up at the same path, so is only committed once.
+    pub fn content_addressed_url(&self, url: &str) -> Option<String> {
+        let index = self.index_of(url)?;
+        let digest = &self.digests[index];
+        match media_paths::extension(url) {
+            Some(ext) => Some(format!("/media/{digest}.{ext}")),
+            None => Some(format!("/media/{digest}")),
         }
     }
 }
 
-// A trait and implementation to download all the media referenced in an RSS entry
+/// The SHA-256 of some content, as hex
+pub fn digest(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+// The URL for a variant sits next to the original: /x/abc.jpeg becomes /x/abc-480w.webp
+fn variant_url(url: &str, variant: &Variant) -> String {
+    let stem = url.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(url);
+    let ext = variant
+        .local_file
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("");
+    format!("{stem}-{}w.{ext}", variant.width)
+}
+
+/// A trait and implementation to download all the media referenced in an RSS entry
 #[async_trait]
 pub trait MediaCopy {
-    async fn download_all(&self, working_dir: &Path) -> Result<LocalMedia, Box<dyn Error>>;
+    /// Download the media to `working_dir`, leaving out sensitive media when `skip_sensitive`
+    async fn download_all(
+        &self,
+        working_dir: &Path,
+        skip_sensitive: bool,
+    ) -> Result<LocalMedia, Box<dyn Error>>;
 }
 
+// For a thread, the media of all its parts, in order
 #[async_trait]
-impl MediaCopy for Item {
-    async fn download_all(&self, working_dir: &Path) -> Result<LocalMedia, Box<dyn Error>> {
+impl MediaCopy for [Item] {
+    async fn download_all(
+        &self,
+        working_dir: &Path,
+        skip_sensitive: bool,
+    ) -> Result<LocalMedia, Box<dyn Error>> {
         let mut map = LocalMedia::new();
         let client = Client::new();
 
-        for media in self.medias() {
+        for media in self.iter().flat_map(|item| item.medias()) {
+            if skip_sensitive && media.is_sensitive() {
+                continue;
+            }
+
             let media_url = media.attrs.get("url").unwrap();
-            let local_file = working_dir.join(Path::new(media_url).file_name().unwrap());
+            // Numbered, as media from different places can have the same file name
+            let file_name = Path::new(media_url).file_name().unwrap().to_string_lossy();
+            let local_file = working_dir.join(format!("{}-{file_name}", map.len() + 1));
 
             let response = client.get(media_url).send().await?;
 
             // We probably have enough memory to read a file into RAM.
             // Unless we don't, in which case this will explode adn we'll need to do a streaming dance.
             let bytes = response.bytes().await?;
+            let digest = digest(&bytes);
+            metrics::inc("mdmd_media_downloaded_bytes_total", &[], bytes.len() as f64);
 
             let mut file = File::create(&local_file).await?;
             file.write_all(&bytes).await?;
 
-            map.push(media_url, &local_file);
+            map.push(media_url, &local_file, &digest);
         }
 
         Ok(map)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_feed_file() {
+        let text = feed_file(Path::new("rss/example01.rss")).await.unwrap();
+        assert_eq!(include_str!("../rss/example01.rss"), text);
+        assert!(feed_file(Path::new("rss/missing.rss")).await.is_err());
+    }
+
+    // A stand-in for a feed server which answers 304 when asked for the version it has
+    #[tokio::test]
+    async fn test_conditional_feed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/@d6y.rss", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                let response = match request.contains("if-none-match: \"v1\"") {
+                    true => "HTTP/1.1 304 Not Modified\r\netag: \"v1\"\r\n\r\n".to_string(),
+                    false => "HTTP/1.1 200 OK\r\netag: \"v1\"\r\ncontent-length: 5\r\n\r\n<rss>"
+                        .to_string(),
+                };
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let client = Client::new();
+        let Feed::Modified { text, validators } = feed(&client, &url, None).await.unwrap() else {
+            panic!("expected the feed");
+        };
+        assert_eq!("<rss>", text);
+        assert_eq!(Some("\"v1\"".to_string()), validators.etag);
+
+        let again = feed(&client, &url, Some(&validators)).await.unwrap();
+        assert!(matches!(again, Feed::NotModified));
+    }
+
+    #[test]
+    fn test_media_ref_without_originals() {
+        let url = "https://files.mastodon.green/a/abc.jpeg";
+        let mut map = LocalMedia::new();
+        map.push(url, Path::new("/tmp/1-abc.jpeg"), "abc");
+        map.set_variants(
+            0,
+            vec![Variant {
+                width: 480,
+                height: 320,
+                local_file: PathBuf::from("/tmp/1-abc-480w.jpeg"),
+            }],
+        );
+        map.set_details(
+            0,
+            Some(ImageDetails {
+                width: 1200,
+                height: 800,
+                blurhash: "LEHV6nWB2yk8pyo0adR*.7kCMdnj".to_string(),
+                colour: "#336699".to_string(),
+            }),
+        );
+
+        let original = map.media_ref(url, "/a/abc.jpeg", true);
+        assert_eq!("/a/abc.jpeg", original.path);
+        assert_eq!(
+            Some((1200, 800)),
+            original.details.map(|d| (d.width, d.height))
+        );
+
+        // The largest variant stands in for the original, with its own size
+        let resized = map.media_ref(url, "/a/abc.jpeg", false);
+        assert_eq!("/a/abc-480w.jpeg", resized.path);
+        assert_eq!(
+            Some((480, 320)),
+            resized.details.map(|d| (d.width, d.height))
+        );
+    }
+
+    #[test]
+    fn test_content_addressed_url() {
+        let mut map = LocalMedia::new();
+        map.push(
+            "https://files.mastodon.green/media_attachments/files/109/848/241/370/274/576/original/c7374d6d7c7ca1ce.jpeg",
+            Path::new("/tmp/c7374d6d7c7ca1ce.jpeg"),
+            "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae",
+        );
+
+        assert_eq!(
+            Some("/media/2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae.jpeg".to_string()),
+            map.content_addressed_url("https://files.mastodon.green/media_attachments/files/109/848/241/370/274/576/original/c7374d6d7c7ca1ce.jpeg")
+        );
+        assert_eq!(
+            None,
+            map.content_addressed_url("https://example.org/other.png")
+        );
+
+        // Any query string isn't part of the extension
+        map.push(
+            "https://cdn.example.org/a.png?size=large",
+            Path::new("/tmp/a.png"),
+            "fcde2b2edba56bf408601fb721fe9b5c338d10ee429ea04fae5511b68fbf8fb9",
+        );
+        assert_eq!(
+            Some(
+                "/media/fcde2b2edba56bf408601fb721fe9b5c338d10ee429ea04fae5511b68fbf8fb9.png"
+                    .to_string()
+            ),
+            map.content_addressed_url("https://cdn.example.org/a.png?size=large")
+        );
+    }
+}
@@ -1,3 +1,6 @@
+use std::sync::LazyLock;
+
+use regex::Regex;
 use rss::{extension::Extension, Channel, Guid, Item};
 
 // The intention here is to extend a `Channel` with functions to let us find relevant `Item`s or their `Guid`s.
@@ -6,6 +9,9 @@ pub trait ChannelSurf {
     /// which we refer to as "the next GUIDs" or "next posts".
     fn find_next_guids(&self, guid: &Guid) -> Vec<&Guid>;
 
+    /// All the GUIDs in the channel up to and including the given GUID, which are the posts we've already seen.
+    fn find_seen_guids(&self, guid: &Guid) -> Vec<&Guid>;
+
     /// Lookup an RSS entry (`Item`) by GUID.
     fn find_by_guid(&self, guid: &Guid) -> Option<&Item>;
 
@@ -18,6 +24,56 @@ pub trait ChannelSurf {
 // The intention here is to be able to fetch all the media (`Entension`s) inside an RSS entry (`Item`)
 pub trait ItemSurf {
     fn medias(&self) -> Vec<&Extension>;
+
+    /// The content warning (spoiler text) of a post, as HTML, if it has one.
+    fn content_warning(&self) -> Option<String>;
+
+    /// The body of the post as HTML, without any content warning.
+    fn body(&self) -> &str;
+}
+
+// Details of a single media item (`Extension`) inside an RSS entry
+pub trait MediaSurf {
+    /// Media marked as sensitive have a rating of "adult" (rather than "nonadult").
+    fn is_sensitive(&self) -> bool;
+}
+
+impl MediaSurf for Extension {
+    fn is_sensitive(&self) -> bool {
+        self.children
+            .get("rating")
+            .and_then(|ratings| ratings.first())
+            .and_then(|rating| rating.value())
+            == Some("adult")
+    }
+}
+
+// Mastodon puts any content warning before the post: <p><strong>Content warning:</strong> Spoilers</p><hr />
+// The label is translated into the language of the post, so we don't rely on the words.
+fn split_content_warning(description: &str) -> (Option<String>, &str) {
+    static PREROLL: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"^\s*<p>\s*<strong>(?s)(.*?)</strong>(.*?)</p>\s*<hr\s*/?>\s*").unwrap()
+    });
+
+    match PREROLL.captures(description) {
+        // A post which happens to start with bold text, and has a rule later on, is not a content warning
+        Some(captures) if captures[2].contains("</p>") => (None, description),
+        Some(captures) => {
+            let label = captures[1].trim();
+            let text = captures[2].trim();
+            // Some versions put the warning inside the <strong> too: <strong>Content warning: Spoilers</strong>
+            let warning = match text.is_empty() {
+                true => label
+                    .split_once(':')
+                    .map(|(_, w)| w.trim())
+                    .unwrap_or(label),
+                false => text,
+            };
+            let rest = &description[captures.get(0).unwrap().end()..];
+            (Some(warning.to_owned()), rest)
+        }
+        None => (None, description),
+    }
 }
 
 impl ItemSurf for Item {
@@ -33,6 +89,14 @@ impl ItemSurf for Item {
         }
         medias
     }
+
+    fn content_warning(&self) -> Option<String> {
+        split_content_warning(self.description().unwrap_or("")).0
+    }
+
+    fn body(&self) -> &str {
+        split_content_warning(self.description().unwrap_or("")).1
+    }
 }
 
 impl ChannelSurf for Channel {
@@ -48,6 +112,18 @@ impl ChannelSurf for Channel {
         candidates
     }
 
+    fn find_seen_guids(&self, from: &Guid) -> Vec<&Guid> {
+        let mut seen: Vec<&Guid> = self
+            .items()
+            .iter()
+            .flat_map(|item| item.guid())
+            .filter(|&g| g.value <= from.value)
+            .collect();
+
+        seen.sort_by_key(|g| &g.value);
+        seen
+    }
+
     fn find_by_guid(&self, guid: &Guid) -> Option<&Item> {
         self.items()
             .iter()
@@ -85,6 +161,23 @@ mod tests {
         assert_eq!(Some(&expected), channel.find_next_guid(&from));
     }
 
+    #[test]
+    fn test_find_seen() {
+        let channel = Channel::from_str(RSS_STR).unwrap();
+
+        let from: Guid = Guid {
+            value: "https://mastodon.green/@d6y/109818375938647316".to_string(),
+            permalink: true,
+        };
+
+        let seen = channel.find_seen_guids(&from);
+        assert_eq!(Some(&&from), seen.last());
+        assert_eq!(
+            channel.items().len(),
+            seen.len() + channel.find_next_guids(&from).len()
+        );
+    }
+
     #[test]
     fn test_find_next_from_latest() {
         let channel = Channel::from_str(RSS_STR).unwrap();
@@ -96,4 +189,51 @@ mod tests {
 
         assert_eq!(None, channel.find_next_guid(&from));
     }
+
+    #[test]
+    fn test_content_warning() {
+        let channel = Channel::from_str(include_str!("../rss/example02.rss")).unwrap();
+        let item = &channel.items()[0];
+
+        assert_eq!(
+            Some("Spoilers for &quot;The Last of Us&quot;".to_string()),
+            item.content_warning()
+        );
+        assert_eq!("<p>That ending, though.</p>", item.body());
+    }
+
+    #[test]
+    fn test_content_warning_variations() {
+        assert_eq!(
+            (Some("Food".to_string()), "<p>Soup</p>"),
+            split_content_warning("<p><strong>Inhaltswarnung: Food</strong></p><hr><p>Soup</p>")
+        );
+        assert_eq!(
+            (None, "<p><strong>Bold</strong> start</p>"),
+            split_content_warning("<p><strong>Bold</strong> start</p>")
+        );
+        assert_eq!(
+            (None, "<p><strong>Bold</strong></p><p>Then</p><hr />"),
+            split_content_warning("<p><strong>Bold</strong></p><p>Then</p><hr />")
+        );
+    }
+
+    #[test]
+    fn test_sensitive_media() {
+        let channel = Channel::from_str(include_str!("../rss/example02.rss")).unwrap();
+        assert!(channel.items()[1].medias()[0].is_sensitive());
+
+        let channel = Channel::from_str(RSS_STR).unwrap();
+        let medias: Vec<&Extension> = channel.items().iter().flat_map(|i| i.medias()).collect();
+        assert_eq!(1, medias.iter().filter(|m| m.is_sensitive()).count());
+    }
+
+    #[test]
+    fn test_no_content_warning() {
+        let channel = Channel::from_str(RSS_STR).unwrap();
+        for item in channel.items() {
+            assert_eq!(None, item.content_warning());
+            assert_eq!(item.description().unwrap(), item.body());
+        }
+    }
 }
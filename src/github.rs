@@ -1,7 +1,9 @@
+use std::collections::HashSet;
 use std::error::Error;
 use std::path::PathBuf;
 
 use base64::{engine::general_purpose, Engine};
+use log::info;
 use rss::Guid;
 use serde::Deserialize;
 use serde_json::json;
@@ -9,27 +11,44 @@ use serde_json::Value;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 
+use crate::metrics;
+
+/// A branch of a Github repository, read and committed to through the API
 pub struct Github {
     token: String,
     repo: String, // Format: user/repo
     branch: String,
+    api: String, // e.g., https://api.github.com
 }
 
 impl Github {
+    /// Access `repo` (user/repo) with a bearer `token`
     pub fn new(token: &str, repo: &str, branch: &str) -> Github {
         Github {
             token: token.to_owned(),
             repo: repo.to_owned(),
             branch: branch.to_owned(),
+            api: "https://api.github.com".to_string(),
+        }
+    }
+
+    // For tests, which stand in for Github with a local server
+    #[cfg(test)]
+    pub(crate) fn with_api(self, api: &str) -> Github {
+        Github {
+            api: api.to_owned(),
+            ..self
         }
     }
 }
 
-/// The content we're dealing with is either a `Path` (i.e., PNG on disk) or `Str` (the Markdown we've created in memory):
+/// The content we're dealing with is either a `Path` (i.e., PNG on disk) or `Str` (the Markdown we've created in memory),
+/// or `Deleted` for a file we're removing from the repository:
 #[derive(Debug, Clone)]
 enum Content {
     Path(PathBuf),
     Str(String),
+    Deleted,
 }
 
 /// To send content to Github, we need the path inside the repository and a way to access the content (e.g., text or image data).
@@ -40,6 +59,7 @@ pub struct NewContent {
 }
 
 impl NewContent {
+    /// A file on disk, committed to `git_path`
     pub fn path(git_path: &str, path: &PathBuf) -> NewContent {
         NewContent {
             git_path: git_path.to_owned(),
@@ -47,12 +67,44 @@ impl NewContent {
         }
     }
 
+    /// Text, committed to `git_path`
     pub fn text(git_path: &str, str: &str) -> NewContent {
         NewContent {
             git_path: git_path.to_owned(),
             content: Content::Str(str.to_owned()),
         }
     }
+
+    /// Removing the file at `git_path`
+    pub fn deletion(git_path: &str) -> NewContent {
+        NewContent {
+            git_path: git_path.to_owned(),
+            content: Content::Deleted,
+        }
+    }
+
+    /// The file on disk to be committed, if the content is a file
+    pub fn file(&self) -> Option<&PathBuf> {
+        match &self.content {
+            Content::Path(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// A file or directory in the repository
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/// The most recent commit on the branch
+#[derive(Debug, Clone)]
+pub struct LastCommit {
+    pub headline: String,
+    pub date: String,
+    pub url: String,
 }
 
 /// Github responses
@@ -69,15 +121,24 @@ struct RepoObject {
 impl Github {
     /// The last RSS Guid we've seen. So we know we want things after this to add as new content.
     pub async fn get_last_guid(&self, path: &str) -> Result<Guid, Box<dyn Error>> {
-        let client = reqwest::Client::new();
+        let text = self
+            .get_text(path)
+            .await?
+            .ok_or_else(|| format!("Unable to extract text from {path}"))?;
 
-        // Remove owner, just repository name:
-        let name = self.repo.split('/').last().unwrap();
+        let guid: Guid = Guid {
+            value: text.to_owned(),
+            permalink: true,
+        };
 
-        let expr = format!("{}:{}", self.branch, path);
+        Ok(guid)
+    }
 
-        let query = json!({
-            "query": r#"
+    /// The contents of a text file on the branch, or `None` if there's no such file.
+    pub async fn get_text(&self, path: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let data = self
+            .graphql(
+                r#"
                 query ($name: String!, $expr: String!) {
                     viewer {
                     repository(name: $name) {
@@ -89,43 +150,186 @@ impl Github {
                     } }
                 }
             "#,
-            "variables": {
-                "name": name,
-                "expr": expr
-            }
+                json!({ "name": self.name(), "expr": self.expression(path) }),
+            )
+            .await
+            .map_err(|e| format!("Error when reading {path}: {e}"))?;
+
+        let text = data["viewer"]["repository"]["object"]["text"]
+            .as_str()
+            .map(|text| text.to_owned());
+
+        Ok(text)
+    }
+
+    /// Does a file already exist at the given path on the branch?
+    pub async fn file_exists(&self, path: &str) -> Result<bool, Box<dyn Error>> {
+        let data = self
+            .graphql(
+                r#"
+                query ($name: String!, $expr: String!) {
+                    viewer {
+                    repository(name: $name) {
+                        object(expression: $expr) {
+                            ... on Blob {
+                                oid
+                            }
+                        }
+                    } }
+                }
+            "#,
+                json!({ "name": self.name(), "expr": self.expression(path) }),
+            )
+            .await
+            .map_err(|e| format!("Error when checking for {path}: {e}"))?;
+
+        Ok(data["viewer"]["repository"]["object"]["oid"].is_string())
+    }
+
+    /// The files and directories inside a directory on the branch, which is empty if there's no such directory.
+    pub async fn list_dir(&self, path: &str) -> Result<Vec<Entry>, Box<dyn Error>> {
+        let data = self
+            .graphql(
+                r#"
+                query ($name: String!, $expr: String!) {
+                    viewer {
+                    repository(name: $name) {
+                        object(expression: $expr) {
+                            ... on Tree {
+                                entries { name type }
+                            }
+                        }
+                    } }
+                }
+            "#,
+                json!({ "name": self.name(), "expr": self.expression(path) }),
+            )
+            .await
+            .map_err(|e| format!("Error when listing {path}: {e}"))?;
+
+        let entries = data["viewer"]["repository"]["object"]["entries"]
+            .as_array()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .map(|entry| Entry {
+                        name: entry["name"].as_str().unwrap_or_default().to_owned(),
+                        is_dir: entry["type"] == "tree",
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(entries)
+    }
+
+    /// The latest commit on the branch, or `None` if the branch doesn't exist.
+    pub async fn last_commit(&self) -> Result<Option<LastCommit>, Box<dyn Error>> {
+        let data = self
+            .graphql(
+                r#"
+                query ($name: String!, $branch: String!) {
+                    viewer {
+                    repository(name: $name) {
+                        ref(qualifiedName: $branch) {
+                            target {
+                                ... on Commit {
+                                    messageHeadline committedDate url
+                                }
+                            }
+                        }
+                    } }
+                }
+            "#,
+                json!({ "name": self.name(), "branch": format!("refs/heads/{}", self.branch) }),
+            )
+            .await
+            .map_err(|e| format!("Error when reading the last commit: {e}"))?;
+
+        let target = &data["viewer"]["repository"]["ref"]["target"];
+        let commit = target["url"].as_str().map(|url| LastCommit {
+            headline: target["messageHeadline"]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            date: target["committedDate"]
+                .as_str()
+                .unwrap_or_default()
+                .to_owned(),
+            url: url.to_owned(),
         });
 
+        Ok(commit)
+    }
+
+    // Send a GraphQL request, returning the `data` of the response, or the errors Github gave
+    async fn graphql(&self, query: &str, variables: Value) -> Result<Value, Box<dyn Error>> {
+        let client = reqwest::Client::new();
+
         let res = client
-            .post("https://api.github.com/graphql")
+            .post(format!("{}/graphql", self.api))
             .header(
                 reqwest::header::AUTHORIZATION,
                 format!("bearer {}", self.token),
             )
             .header(reqwest::header::USER_AGENT, &self.repo)
-            .json(&query)
+            .json(&json!({ "query": query, "variables": variables }))
             .send()
-            .await?;
+            .await
+            .inspect_err(|_| metrics::api_error("graphql"))?;
 
-        let response_body: Value = res.json().await?;
+        let mut response_body: Value = res
+            .json()
+            .await
+            .inspect_err(|_| metrics::api_error("graphql"))?;
 
-        let text = response_body["data"]["viewer"]["repository"]["object"]["text"]
-            .as_str()
-            .unwrap_or_else(|| panic!("Unable to extract text from {response_body:?}"));
+        if let Some(errors) = response_body.get("errors") {
+            metrics::api_error("graphql");
+            return Err(errors.to_string().into());
+        }
 
-        let guid: Guid = Guid {
-            value: text.to_owned(),
-            permalink: true,
-        };
+        Ok(response_body["data"].take())
+    }
 
-        Ok(guid)
+    // The repository name, without the owner
+    fn name(&self) -> &str {
+        self.repo.split('/').next_back().unwrap_or(&self.repo)
+    }
+
+    // A path on the branch, as a Git object expression
+    fn expression(&self, path: &str) -> String {
+        format!("{}:{}", self.branch, path)
+    }
+
+    /// Remove file content (e.g., images) which is already in the repository, or repeated in the list.
+    /// Text content is always kept as it is expected to change (e.g., the last GUID).
+    /// This is only worth doing when media paths are content addressed, as otherwise each media file has a unique path.
+    pub async fn without_existing(
+        &self,
+        contents: Vec<NewContent>,
+    ) -> Result<Vec<NewContent>, Box<dyn Error>> {
+        let mut seen = HashSet::new();
+        let mut novel = Vec::new();
+        for content in contents {
+            let is_file = matches!(content.content, Content::Path(_));
+            if is_file
+                && (!seen.insert(content.git_path.clone())
+                    || self.file_exists(&content.git_path).await?)
+            {
+                info!("skipping {}: already in repository", content.git_path);
+                continue;
+            }
+            novel.push(content);
+        }
+        Ok(novel)
     }
 
     /// Fetch the repository OID: this is the state if the repository,
     /// distinct from the last GUID, which is the last seen RSS entry.
     async fn get_oid(&self) -> Result<String, Box<dyn std::error::Error>> {
         let url = format!(
-            "https://api.github.com/repos/{}/git/ref/heads/{}",
-            self.repo, self.branch
+            "{}/repos/{}/git/ref/heads/{}",
+            self.api, self.repo, self.branch
         );
         let client = reqwest::Client::new();
         let res = client
@@ -136,22 +340,26 @@ impl Github {
             )
             .header(reqwest::header::USER_AGENT, &self.repo)
             .send()
-            .await?
+            .await
+            .inspect_err(|_| metrics::api_error("get_oid"))?
             .text()
             .await?;
 
         match serde_json::from_str::<RepoState>(&res) {
             Ok(state) => Ok(state.object.sha),
-            Err(_) => panic!("Unexpected JSON from get_oid call: {}", res),
+            Err(_) => {
+                metrics::api_error("get_oid");
+                Err(format!("Unexpected JSON from get_oid call: {res}").into())
+            }
         }
     }
 
-    /// Commit the content
+    /// Commit the content, returning the URL of the commit
     pub async fn commit(
         &self,
         commit_msg: &str,
         content: &[NewContent],
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<String, Box<dyn std::error::Error>> {
         let oid = self.get_oid().await?;
         self.add_files(&oid, commit_msg, content).await
     }
@@ -162,50 +370,42 @@ impl Github {
         oid: &str,
         commit_msg: &str,
         content: &[NewContent],
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let payload: String = self.mutation_json(oid, commit_msg, content).await?;
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let variables = self.mutation_variables(oid, commit_msg, content).await?;
 
-        let client = reqwest::Client::new();
-
-        let res = client
-            .post("https://api.github.com/graphql")
-            .header(
-                reqwest::header::AUTHORIZATION,
-                format!("bearer {}", self.token),
+        let data = self
+            .graphql(
+                "mutation ($input: CreateCommitOnBranchInput!) { createCommitOnBranch(input: $input) { commit { url } } }",
+                variables,
             )
-            .header(reqwest::header::USER_AGENT, &self.repo)
-            .body(payload)
-            .send()
-            .await?
-            .text()
-            .await?;
+            .await
+            .map_err(|e| format!("Error when commiting {commit_msg}: {e}"))?;
 
-        let v: Value = serde_json::from_str(&res)?;
-
-        match v.get("errors") {
-            Some(_) => panic!("Error when commiting file: {}", res),
-            None => Ok(()),
-        }
+        Ok(data["createCommitOnBranch"]["commit"]["url"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned())
     }
 
-    /// Handle the creation of the GraphQL Json, including BASE64 encoding the content
-    async fn mutation_json(
+    /// Handle the creation of the GraphQL variables, including BASE64 encoding the content
+    async fn mutation_variables(
         &self,
         oid: &str,
         commit_msg: &str,
         contents: &[NewContent],
-    ) -> Result<String, Box<dyn Error>> {
+    ) -> Result<Value, Box<dyn Error>> {
         let mut additions = Vec::new();
+        let mut deletions = Vec::new();
         for content in contents {
-            let val = Github::to_addition(content).await?;
-            additions.push(val);
+            match content.content {
+                Content::Deleted => deletions.push(json!({ "path": content.git_path })),
+                _ => additions.push(Github::to_addition(content).await?),
+            }
         }
 
         // NB: CreateBlob+createTree+CreateCommitOnBranchInput+updateRef may be an alaternative if file size is an issue.
 
-        let payload = json!({
-            "query": "mutation ($input: CreateCommitOnBranchInput!) { createCommitOnBranch(input: $input) { commit { url } } }",
-            "variables": {
+        let variables = json!({
             "input": {
                 "branch": {
                     "repositoryNameWithOwner": format!("{}", self.repo),
@@ -213,14 +413,14 @@ impl Github {
                 },
                 "message": { "headline": format!("{commit_msg}") },
                 "fileChanges": {
-                    "additions": additions
+                    "additions": additions,
+                    "deletions": deletions
                 },
                 "expectedHeadOid": format!("{oid}")
             }
-            }
         });
 
-        Ok(payload.to_string())
+        Ok(variables)
     }
 
     // Read a single file and turn it into Json for inclusion in the GraphQL
@@ -230,6 +430,9 @@ impl Github {
         let b64_content = match &content.content {
             Content::Path(p) => general_purpose::STANDARD.encode(Github::read_file(p).await?),
             Content::Str(s) => general_purpose::STANDARD.encode(s),
+            Content::Deleted => {
+                return Err(format!("{} is a deletion, not an addition", content.git_path).into())
+            }
         };
 
         Ok(json!({
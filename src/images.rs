@@ -0,0 +1,201 @@
+use std::error::Error;
+use std::path::Path;
+
+use clap::ValueEnum;
+use image::{imageops::FilterType, DynamicImage, ImageFormat, ImageReader};
+use log::info;
+
+use crate::download::{LocalMedia, Variant};
+
+/// The file format to use for resized images
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum VariantFormat {
+    /// Keep the format of the downloaded image
+    #[default]
+    Original,
+    Webp,
+    Avif,
+}
+
+/// What we know about an image after downloading it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageDetails {
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: String,
+    /// The average colour, as #rrggbb
+    pub colour: String,
+}
+
+/// Dimensions, blurhash, and colour of an image
+pub fn describe(img: &DynamicImage) -> Result<ImageDetails, Box<dyn Error>> {
+    // A blurhash only captures a handful of components, so a small thumbnail is plenty
+    let thumbnail = img.thumbnail(32, 32).to_rgba8();
+    let blurhash = blurhash::encode(
+        4,
+        3,
+        thumbnail.width(),
+        thumbnail.height(),
+        thumbnail.as_raw(),
+    )?;
+
+    let pixels = thumbnail.pixels().len() as u64;
+    let [r, g, b] = [0, 1, 2]
+        .map(|channel| thumbnail.pixels().map(|p| p[channel] as u64).sum::<u64>() / pixels);
+
+    Ok(ImageDetails {
+        width: img.width(),
+        height: img.height(),
+        blurhash,
+        colour: format!("#{r:02x}{g:02x}{b:02x}"),
+    })
+}
+
+// The image in a file, or `None` for media which are not still images
+fn decode(path: &Path) -> Result<Option<DynamicImage>, Box<dyn Error>> {
+    if still_image_format(path).is_none() {
+        return Ok(None);
+    }
+    Ok(Some(
+        ImageReader::open(path)?.with_guessed_format()?.decode()?,
+    ))
+}
+
+// Only still images we know how to decode. Anything else (video, audio) is left alone.
+fn still_image_format(path: &Path) -> Option<ImageFormat> {
+    match ImageFormat::from_path(path) {
+        Ok(format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)) => Some(format),
+        _ => None,
+    }
+}
+
+/// An optional stage after downloading, to produce smaller versions of images for responsive markup,
+/// and to measure images so the markdown can include sizes and placeholders.
+pub struct Resizer {
+    widths: Vec<u32>,
+    format: VariantFormat,
+    details: bool,
+}
+
+impl Resizer {
+    /// Resize images to each of `widths`, writing them as `format`
+    pub fn new(widths: &[u32], format: VariantFormat) -> Resizer {
+        Resizer {
+            widths: widths.to_owned(),
+            format,
+            details: false,
+        }
+    }
+
+    /// Measure each image as well
+    pub fn with_details(self, details: bool) -> Resizer {
+        Resizer { details, ..self }
+    }
+
+    /// Create resized variants of every image in `media`, writing them alongside the downloaded files,
+    /// and measure them if asked. Each image is decoded once for both.
+    pub fn resize_all(&self, media: &mut LocalMedia) -> Result<(), Box<dyn Error>> {
+        for index in 0..media.len() {
+            let original = media.local_files()[index].to_owned();
+            let Some(img) = decode(&original)? else {
+                continue;
+            };
+            let variants = self.resize(&original, &img)?;
+            media.set_variants(index, variants);
+            if self.details {
+                media.set_details(index, Some(describe(&img)?));
+            }
+        }
+        Ok(())
+    }
+
+    fn resize(&self, original: &Path, img: &DynamicImage) -> Result<Vec<Variant>, Box<dyn Error>> {
+        let Some(original_format) = still_image_format(original) else {
+            return Ok(Vec::new());
+        };
+
+        let (format, ext) = match self.format {
+            VariantFormat::Original => (
+                original_format,
+                original.extension().and_then(|e| e.to_str()).unwrap_or(""),
+            ),
+            VariantFormat::Webp => (ImageFormat::WebP, "webp"),
+            VariantFormat::Avif => (ImageFormat::Avif, "avif"),
+        };
+
+        let stem = original.file_stem().and_then(|s| s.to_str()).unwrap();
+
+        let mut variants = Vec::new();
+        for &width in &self.widths {
+            // There's no value in making an image larger than the original
+            if width >= img.width() {
+                continue;
+            }
+
+            let resized = img.resize(width, u32::MAX, FilterType::Lanczos3);
+            let height = resized.height();
+            let local_file = original.with_file_name(format!("{stem}-{width}w.{ext}"));
+            encodable(resized, format).save_with_format(&local_file, format)?;
+
+            info!("resized {stem} to {width}w");
+            variants.push(Variant {
+                width,
+                height,
+                local_file,
+            });
+        }
+
+        Ok(variants)
+    }
+}
+
+// Encoders only accept some pixel layouts (e.g., JPEG has no alpha channel)
+fn encodable(img: DynamicImage, format: ImageFormat) -> DynamicImage {
+    match format {
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ImageFormat::WebP | ImageFormat::Avif => DynamicImage::ImageRgba8(img.to_rgba8()),
+        _ => img,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_resize_skips_upscaling() {
+        let dir = TempDir::new().unwrap();
+        let original = dir.path().join("abc.png");
+        RgbImage::new(100, 50).save(&original).unwrap();
+
+        let resizer = Resizer::new(&[40, 200], VariantFormat::Webp);
+        let img = decode(&original).unwrap().unwrap();
+        let variants = resizer.resize(&original, &img).unwrap();
+
+        assert_eq!(1, variants.len());
+        assert_eq!((40, 20), (variants[0].width, variants[0].height));
+        assert_eq!(dir.path().join("abc-40w.webp"), variants[0].local_file);
+        assert_eq!(
+            (40, 20),
+            image::image_dimensions(&variants[0].local_file).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("red.png");
+        RgbImage::from_pixel(30, 20, image::Rgb([255, 0, 0]))
+            .save(&path)
+            .unwrap();
+
+        let details = describe(&decode(&path).unwrap().unwrap()).unwrap();
+        assert_eq!((30, 20), (details.width, details.height));
+        assert_eq!("#ff0000", details.colour);
+        assert!(!details.blurhash.is_empty());
+
+        assert!(decode(&dir.path().join("clip.mp4")).unwrap().is_none());
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+use base64::{engine::general_purpose, Engine};
+use log::info;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::download;
+use crate::metrics;
+
+const LFS_MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+
+/// Git LFS for a Github repository: file content goes to LFS storage, and the repository holds a small pointer file.
+pub struct Lfs {
+    endpoint: String, // e.g., https://github.com/user/repo.git/info/lfs
+    token: String,
+    branch: String,
+}
+
+/// Github LFS batch API responses
+#[derive(Deserialize, Debug)]
+struct BatchResponse {
+    objects: Vec<BatchObject>,
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchObject {
+    oid: String,
+    actions: Option<Actions>,
+    error: Option<ObjectError>,
+}
+
+/// No actions means the server already has the object
+#[derive(Deserialize, Debug)]
+struct Actions {
+    upload: Option<Action>,
+    verify: Option<Action>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Action {
+    href: String,
+    #[serde(default)]
+    header: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ObjectError {
+    code: u16,
+    message: String,
+}
+
+impl Lfs {
+    /// The LFS storage of `repo` (user/repo), uploading for `branch`
+    pub fn new(repo: &str, token: &str, branch: &str) -> Lfs {
+        Lfs {
+            endpoint: format!("https://github.com/{repo}.git/info/lfs"),
+            token: token.to_owned(),
+            branch: branch.to_owned(),
+        }
+    }
+
+    /// Upload a file to LFS storage (if it isn't there already), returning the pointer file to commit in its place.
+    pub async fn upload(&self, path: &Path) -> Result<String, Box<dyn Error>> {
+        let bytes = tokio::fs::read(path).await?;
+        let oid = download::digest(&bytes);
+        let size = bytes.len();
+
+        let client = Client::new();
+        let auth = general_purpose::STANDARD.encode(format!("x-access-token:{}", self.token));
+
+        let request = json!({
+            "operation": "upload",
+            "transfers": ["basic"],
+            "ref": { "name": format!("refs/heads/{}", self.branch) },
+            "objects": [{ "oid": oid, "size": size }]
+        });
+
+        let res = client
+            .post(format!("{}/objects/batch", self.endpoint))
+            .header(reqwest::header::AUTHORIZATION, format!("Basic {auth}"))
+            .header(reqwest::header::ACCEPT, LFS_MEDIA_TYPE)
+            .header(reqwest::header::CONTENT_TYPE, LFS_MEDIA_TYPE)
+            .body(request.to_string())
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        let batch: BatchResponse = match serde_json::from_str(&res) {
+            Ok(batch) => batch,
+            Err(_) => return Err(format!("Unexpected JSON from LFS batch call: {res}").into()),
+        };
+
+        for object in batch.objects.iter().filter(|o| o.oid == oid) {
+            if let Some(error) = &object.error {
+                return Err(
+                    format!("LFS error {} for {oid}: {}", error.code, error.message).into(),
+                );
+            }
+
+            let Some(actions) = &object.actions else {
+                info!("{} already in LFS", path.display());
+                continue;
+            };
+
+            if let Some(upload) = &actions.upload {
+                let mut req = client.put(&upload.href).body(bytes.to_owned());
+                for (name, value) in &upload.header {
+                    req = req.header(name, value);
+                }
+                req.header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                metrics::inc("mdmd_media_uploaded_bytes_total", &[], size as f64);
+            }
+
+            if let Some(verify) = &actions.verify {
+                let mut req = client
+                    .post(&verify.href)
+                    .header(reqwest::header::ACCEPT, LFS_MEDIA_TYPE)
+                    .header(reqwest::header::CONTENT_TYPE, LFS_MEDIA_TYPE)
+                    .body(json!({ "oid": oid, "size": size }).to_string());
+                for (name, value) in &verify.header {
+                    req = req.header(name, value);
+                }
+                req.send().await?.error_for_status()?;
+            }
+        }
+
+        Ok(pointer(&oid, size))
+    }
+}
+
+/// The text committed in place of a file stored in LFS
+pub fn pointer(oid: &str, size: usize) -> String {
+    format!("version https://git-lfs.github.com/spec/v1\noid sha256:{oid}\nsize {size}\n")
+}
+
+/// The .gitattributes line which routes files matching `pattern` through LFS
+pub fn rule(pattern: &str) -> String {
+    format!("{pattern} filter=lfs diff=lfs merge=lfs -text")
+}
+
+/// Add any missing rules to the contents of a .gitattributes file.
+/// `None` means all the rules are already there.
+pub fn with_rules(gitattributes: &str, rules: &[String]) -> Option<String> {
+    let missing: Vec<&String> = rules
+        .iter()
+        .filter(|rule| {
+            !gitattributes
+                .lines()
+                .any(|line| line.trim() == rule.as_str())
+        })
+        .collect();
+
+    if missing.is_empty() {
+        return None;
+    }
+
+    let mut updated = gitattributes.to_owned();
+    if !updated.is_empty() && !updated.ends_with('\n') {
+        updated.push('\n');
+    }
+    for rule in missing {
+        updated.push_str(rule);
+        updated.push('\n');
+    }
+    Some(updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_pointer() {
+        let expected = "version https://git-lfs.github.com/spec/v1
+oid sha256:4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393
+size 12345
+";
+        assert_eq!(
+            expected,
+            pointer(
+                "4d7a214614ab2935c943f9e0ff69d22eadbb8f32b1258daaa5e2ca24d17e2393",
+                12345
+            )
+        );
+    }
+
+    #[test]
+    fn test_with_rules() {
+        let jpeg = rule("static/**/*.jpeg");
+        let mp4 = rule("static/**/*.mp4");
+
+        let existing = "*.psd binary\nstatic/**/*.jpeg filter=lfs diff=lfs merge=lfs -text";
+        let expected = "*.psd binary\nstatic/**/*.jpeg filter=lfs diff=lfs merge=lfs -text\nstatic/**/*.mp4 filter=lfs diff=lfs merge=lfs -text\n";
+
+        assert_eq!(
+            Some(expected.to_string()),
+            with_rules(existing, &[jpeg.clone(), mp4])
+        );
+        assert_eq!(None, with_rules(existing, &[jpeg]));
+    }
+
+    #[test]
+    fn test_batch_response() {
+        let res = r#"{
+            "transfer": "basic",
+            "objects": [
+                { "oid": "1111", "size": 1, "authenticated": true },
+                { "oid": "2222", "size": 2, "actions": {
+                    "upload": { "href": "https://example.org/upload", "header": { "Authorization": "RemoteAuth abc" } },
+                    "verify": { "href": "https://example.org/verify" }
+                } },
+                { "oid": "3333", "size": 3, "error": { "code": 422, "message": "Object too large" } }
+            ]
+        }"#;
+
+        let batch: BatchResponse = serde_json::from_str(res).unwrap();
+
+        assert!(batch.objects[0].actions.is_none());
+        let actions = batch.objects[1].actions.as_ref().unwrap();
+        assert_eq!(
+            "RemoteAuth abc",
+            actions.upload.as_ref().unwrap().header["Authorization"]
+        );
+        assert!(actions.verify.as_ref().unwrap().header.is_empty());
+        assert_eq!(422, batch.objects[2].error.as_ref().unwrap().code);
+    }
+}
@@ -0,0 +1,753 @@
+//! Archive Mastodon toots to a Github repository as markdown, for Hugo and similar static site generators.
+//!
+//! The `mdmd` binary is a thin command line over this library. To archive from your own tooling:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let settings = mdmd::Settings::builder("github-token", "d6y/blog")
+//!     .feed("https://mastodon.green/@d6y.rss")
+//!     .instance("mastodon.green")
+//!     .num_posts(10)
+//!     .build()?;
+//! let outcome = mdmd::run(&settings, &mdmd::Command::Sync).await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! For more control, an [`archive::Archiver`] turns feed items into [`archive::Post`]s, without committing anything.
+
+use chrono::{DateTime, Duration, NaiveDate, SecondsFormat, Utc};
+use clap::ValueEnum;
+use log::{info, warn};
+use rss::{Channel, Guid, Item};
+use std::{
+    error::Error,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::feed::ChannelSurf;
+
+/// Turning feed items into posts
+pub mod archive;
+mod cache;
+/// Downloading feeds and media
+pub mod download;
+/// Reading RSS feeds from Mastodon
+pub mod feed;
+/// Reading and committing to a Github repository
+pub mod github;
+/// Measuring, resizing and describing images
+pub mod images;
+/// Uploading media to Git LFS storage
+pub mod lfs;
+/// Rendering feed items as markdown
+pub mod markdown;
+mod mastodon;
+mod media;
+mod media_paths;
+/// Prometheus metrics
+pub mod metrics;
+mod privacy;
+/// Machine readable reports of each run
+pub mod report;
+/// Uploading media to an S3-compatible bucket
+pub mod s3;
+mod settings;
+use archive::Archiver;
+
+pub use github::LastCommit;
+pub use images::VariantFormat;
+pub use markdown::{ContentWarnings, ImageMarkup, ReplyContext, SensitiveMedia};
+pub use media::MediaStorage;
+pub use media_paths::{MediaLayout, Rule};
+pub use settings::{Settings, SettingsBuilder};
+
+/// What to do with a feed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Command {
+    /// Archive new posts from the feed
+    #[default]
+    Sync,
+    /// Find the last GUID archived, how many posts in the feed are waiting to be archived, and the last commit
+    Status,
+    /// Render the markdown for one post, given by GUID or URL, without committing anything
+    Render { item: String },
+    /// Move the last GUID, so the next sync archives the posts after it.
+    /// Unless `force`, the GUID must be in the feed, or look like a toot on the instance.
+    SetCursor { guid: String, force: bool },
+    /// Re-render posts we've already archived, committing any that have changed (e.g., an edited toot)
+    Update,
+    /// Find archived posts from the last `window_days` whose toots have been deleted, and only when `confirm`, remove them from the blog
+    Reconcile {
+        window_days: i64,
+        deleted: Deleted,
+        confirm: bool,
+    },
+}
+
+/// What happens to the archived copy of a deleted toot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Deleted {
+    /// Keep the post, but mark it `draft: true` so it isn't published
+    Draft,
+    /// Delete the markdown and its media from the repository
+    Delete,
+}
+
+/// What a command did
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    /// The number of posts archived or changed (for set-cursor, 1 when the cursor moved)
+    Changed(usize),
+    Status(Status),
+    /// The markdown of a post
+    Rendered(String),
+    Reconciled(Reconciliation),
+}
+
+/// Where an archive is up to
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub feed: String,
+    pub last_guid: String,
+    /// Posts in the feed after the last GUID
+    pub pending: usize,
+    /// `None` when the branch has no commits
+    pub last_commit: Option<LastCommit>,
+}
+
+/// Archived posts whose toots no longer exist
+#[derive(Debug, Clone)]
+pub struct Reconciliation {
+    /// Posts from before this date weren't checked
+    pub since: NaiveDate,
+    /// Pairs of `(markdown path, toot id)`
+    pub gone: Vec<(String, String)>,
+    /// Whether the changes were committed, or this was a dry run
+    pub committed: bool,
+}
+
+impl Outcome {
+    /// The number of posts changed in the repository
+    pub fn changed(&self) -> usize {
+        match self {
+            Outcome::Changed(changed) => *changed,
+            Outcome::Reconciled(reconciliation) if reconciliation.committed => {
+                reconciliation.gone.len()
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Run a command over one feed
+pub async fn run(settings: &Settings, command: &Command) -> Result<Outcome, Box<dyn Error>> {
+    let mut archiver = Archiver::new(settings)?;
+    run_with(&mut archiver, command, &AtomicBool::new(false)).await
+}
+
+/// Run a command with an archiver which may have been used before (e.g., polling a feed), writing a report if asked.
+/// Once `stopping` is set, no more posts are started.
+pub async fn run_with(
+    archiver: &mut Archiver<'_>,
+    command: &Command,
+    stopping: &AtomicBool,
+) -> Result<Outcome, Box<dyn Error>> {
+    archiver.begin();
+    let result = run_command(archiver, command, stopping).await;
+
+    if let Some(path) = &archiver.settings().report {
+        let report = archiver.report();
+        report.finish(&result);
+        // The run's own result matters more than its report
+        if let Err(err) = report.write(path) {
+            warn!("Unable to write the report to {}: {err}", path.display());
+        }
+    }
+    result
+}
+
+async fn run_command(
+    archiver: &mut Archiver<'_>,
+    command: &Command,
+    stopping: &AtomicBool,
+) -> Result<Outcome, Box<dyn Error>> {
+    let settings = archiver.settings();
+
+    // Moving the cursor, and checking archived posts against the instance, don't need the feed
+    match command {
+        Command::SetCursor { guid, force } => {
+            return set_cursor(settings, archiver, guid, *force).await
+        }
+        Command::Reconcile {
+            window_days,
+            deleted,
+            confirm,
+        } => return reconcile(settings, archiver, *window_days, *deleted, *confirm).await,
+        _ => {}
+    }
+
+    // Only a sync can skip a feed which hasn't changed, as other commands look at posts we've already seen
+    let conditional = *command == Command::Sync;
+
+    let Some(rss_str) = archiver.feed(conditional).await? else {
+        info!("{}: not modified", settings.feed);
+        return Ok(Outcome::Changed(0));
+    };
+
+    let channel = Channel::from_str(&rss_str)?;
+
+    match command {
+        Command::Sync => sync(settings, &channel, archiver, stopping).await,
+        Command::Update => update(settings, &channel, archiver).await,
+        Command::Status => status(settings, &channel, archiver).await,
+        Command::Render { item } => render(&channel, archiver, item).await,
+        Command::SetCursor { .. } | Command::Reconcile { .. } => {
+            unreachable!("handled before the feed")
+        }
+    }
+}
+
+// Archive posts after the last GUID, one commit per post
+async fn sync(
+    settings: &Settings,
+    channel: &Channel,
+    archiver: &mut Archiver<'_>,
+    stopping: &AtomicBool,
+) -> Result<Outcome, Box<dyn Error>> {
+    let from = archiver.last_guid().await?;
+    archiver.report().last_guid_before = Some(from.value().to_owned());
+
+    // Skipped posts don't count towards `num_posts`. They are passed over by the last GUID of the next post we do archive,
+    // or, when they come last, by committing the last GUID on its own.
+    let mut published = 0;
+    let mut passed_over = None;
+
+    // Whether we got through every new post, rather than stopping at `num_posts` or on a signal
+    let mut finished = true;
+
+    for guid in channel.find_next_guids(&from) {
+        if published == settings.num_posts || stopping.load(Ordering::SeqCst) {
+            finished = false;
+            break;
+        }
+
+        // Locate the basic post details:
+        let item = channel.find_by_guid(guid).unwrap();
+
+        let Some(draft) = archiver.draft(item).await? else {
+            passed_over = Some(guid);
+            continue;
+        };
+
+        // A later part of a thread comes back as the whole thread, which an earlier sync may have written already.
+        // It's only written again when it has changed, such as when the thread has grown.
+        if draft.is_thread() && archiver.archived(&draft).await? {
+            info!("skipping {}: thread already archived", guid.value());
+            archiver
+                .report()
+                .skip(guid.value(), "thread already archived");
+            passed_over = Some(guid);
+            continue;
+        }
+        passed_over = None;
+
+        let post = archiver.complete(draft).await?;
+
+        // Convert into Github new content, which is a path in the repo and the content (as text or a path on disk).
+        let mut new_content = archiver.publish_media(&post).await?;
+
+        let markdown = markdown::with_content_hash(&post.markdown, &post.content_hash, None);
+        new_content.push(github::NewContent::text(&post.markdown_path, &markdown));
+
+        // ...updating the next GUID file is also a "new content":
+        let id_content = github::NewContent::text(&settings.last_guid_git_path, guid.value());
+        new_content.push(id_content);
+
+        info!("{}", post.filename);
+        let commit_url = archiver
+            .commit(&format!("add {}", post.filename), new_content)
+            .await?;
+        archiver.set_last_guid(guid);
+        archiver
+            .report()
+            .published
+            .push(post.published(&commit_url));
+        metrics::inc(
+            "mdmd_posts_published_total",
+            &[("feed", &settings.feed)],
+            1.0,
+        );
+        published += 1;
+    }
+
+    if let Some(guid) = passed_over {
+        let id_content = github::NewContent::text(&settings.last_guid_git_path, guid.value());
+        archiver
+            .commit(&format!("skip to {}", guid.value()), vec![id_content])
+            .await?;
+        archiver.set_last_guid(guid);
+    }
+
+    let to = archiver.last_guid().await?;
+    archiver.report().last_guid_after = Some(to.value().to_owned());
+    record_lag(settings, channel, &to);
+
+    if finished {
+        archiver.feed_finished()?;
+    }
+
+    Ok(Outcome::Changed(published))
+}
+
+// Where we are: the last GUID, how far behind the feed that is, and the last change to the repository
+async fn status(
+    settings: &Settings,
+    channel: &Channel,
+    archiver: &mut Archiver<'_>,
+) -> Result<Outcome, Box<dyn Error>> {
+    let from = archiver.last_guid().await?;
+    let pending = channel.find_next_guids(&from).len();
+
+    Ok(Outcome::Status(Status {
+        feed: settings.feed.clone(),
+        last_guid: from.value().to_owned(),
+        pending,
+        last_commit: archiver.github().last_commit().await?,
+    }))
+}
+
+// The markdown for a toot, found in the feed by GUID or URL, or failing that fetched from the instance
+async fn render(
+    channel: &Channel,
+    archiver: &mut Archiver<'_>,
+    guid_or_url: &str,
+) -> Result<Outcome, Box<dyn Error>> {
+    let in_feed = channel.items().iter().find(|item| {
+        item.guid().map(|g| g.value()) == Some(guid_or_url) || item.link() == Some(guid_or_url)
+    });
+    let item = match in_feed {
+        Some(item) => item.clone(),
+        None => {
+            let id = guid_or_url.split('/').next_back().unwrap_or(guid_or_url);
+            match archiver.mastodon().status(id).await? {
+                Some(status) => status.to_item(),
+                None => return Err(format!("No toot found for {guid_or_url}").into()),
+            }
+        }
+    };
+
+    match archiver.prepare(&item).await? {
+        Some(post) => Ok(Outcome::Rendered(markdown::with_content_hash(
+            &post.markdown,
+            &post.content_hash,
+            None,
+        ))),
+        None => Err(format!("{guid_or_url} would not be archived").into()),
+    }
+}
+
+// Commit a new last GUID, moving where the next sync starts from.
+// The feed is only fetched when the GUID doesn't look like one of our toots.
+async fn set_cursor(
+    settings: &Settings,
+    archiver: &mut Archiver<'_>,
+    guid: &str,
+    force: bool,
+) -> Result<Outcome, Box<dyn Error>> {
+    let new_guid = Guid {
+        value: guid.to_owned(),
+        permalink: true,
+    };
+    if !force && !mastodon::is_status_url(&settings.instance, guid) {
+        let in_feed = match archiver.feed(false).await? {
+            Some(rss) => Channel::from_str(&rss)?.find_by_guid(&new_guid).is_some(),
+            None => false,
+        };
+        if !in_feed {
+            return Err(format!(
+                "{guid} is not in the feed, nor a toot on {}. Use --force to set it anyway",
+                settings.instance
+            )
+            .into());
+        }
+    }
+
+    let from = archiver.last_guid().await?;
+    info!("last guid was {}", from.value());
+    archiver.report().last_guid_before = Some(from.value().to_owned());
+
+    let id_content = github::NewContent::text(&settings.last_guid_git_path, guid);
+    archiver
+        .commit(&format!("set last guid to {guid}"), vec![id_content])
+        .await?;
+    archiver.set_last_guid(&new_guid);
+    archiver.report().last_guid_after = Some(guid.to_owned());
+    Ok(Outcome::Changed(1))
+}
+
+// How far the archive is behind the feed, by when the toots were posted.
+// The newest archived toot is only known while it is still in the feed.
+fn record_lag(settings: &Settings, channel: &Channel, last_guid: &Guid) {
+    let posted = |item: &Item| {
+        item.pub_date()
+            .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+            .map(|date| date.timestamp() as f64)
+    };
+    let feed = [("feed", settings.feed.as_str())];
+
+    let newest = channel.items().iter().filter_map(posted).reduce(f64::max);
+    let archived = channel.find_by_guid(last_guid).and_then(posted);
+    if let Some(newest) = newest {
+        metrics::set("mdmd_newest_feed_toot_timestamp_seconds", &feed, newest);
+    }
+    if let Some(archived) = archived {
+        metrics::set(
+            "mdmd_newest_archived_toot_timestamp_seconds",
+            &feed,
+            archived,
+        );
+    }
+    if let (Some(newest), Some(archived)) = (newest, archived) {
+        metrics::set("mdmd_archive_lag_seconds", &feed, newest - archived);
+    }
+}
+
+// Re-render the posts in the feed we have already archived, and commit those which are different to the repository
+async fn update(
+    settings: &Settings,
+    channel: &Channel,
+    archiver: &mut Archiver<'_>,
+) -> Result<Outcome, Box<dyn Error>> {
+    let from = archiver.last_guid().await?;
+
+    let mut updated = 0;
+
+    for guid in channel.find_seen_guids(&from) {
+        let item = channel.find_by_guid(guid).unwrap();
+
+        let Some(draft) = archiver.draft(item).await? else {
+            continue;
+        };
+
+        let Some(archived) = archiver.github().get_text(&draft.markdown_path).await? else {
+            info!("skipping {}: not in repository", draft.markdown_path);
+            archiver.report().skip(guid.value(), "not in repository");
+            continue;
+        };
+
+        // Media are only fetched for posts which have changed, or which we can't tell about without them.
+        // Posts archived before we recorded hashes are compared as they are.
+        let changed = match markdown::archived_content_hash(&archived) {
+            Some(hash) if hash == draft.content_hash => None,
+            Some(_) => Some(archiver.complete(draft).await?),
+            None => {
+                let post = archiver.complete(draft).await?;
+                (post.markdown != archived).then_some(post)
+            }
+        };
+        let Some(post) = changed else {
+            archiver.report().skip(guid.value(), "unchanged");
+            continue;
+        };
+
+        let mut new_content = archiver.publish_media(&post).await?;
+
+        let now = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, false);
+        let markdown = markdown::with_content_hash(&post.markdown, &post.content_hash, Some(&now));
+        new_content.push(github::NewContent::text(&post.markdown_path, &markdown));
+
+        info!("{} changed", post.filename);
+        let commit_url = archiver
+            .commit(&format!("update {}", post.filename), new_content)
+            .await?;
+        archiver
+            .report()
+            .published
+            .push(post.published(&commit_url));
+        metrics::inc(
+            "mdmd_posts_published_total",
+            &[("feed", &settings.feed)],
+            1.0,
+        );
+        updated += 1;
+    }
+
+    Ok(Outcome::Changed(updated))
+}
+
+// Check recent posts against the instance, and draft or delete those whose toots no longer exist.
+// A toot which isn't public any more looks the same as one which has been deleted.
+async fn reconcile(
+    settings: &Settings,
+    archiver: &Archiver<'_>,
+    window_days: i64,
+    deleted: Deleted,
+    confirm: bool,
+) -> Result<Outcome, Box<dyn Error>> {
+    let gh = archiver.github();
+    let since = Utc::now().date_naive() - Duration::days(window_days);
+
+    let mut changes: Vec<github::NewContent> = Vec::new();
+    let mut gone = Vec::new();
+
+    for entry in gh.list_dir(&settings.post_path).await? {
+        let Some((date, id)) = markdown::parse_post_slug(&entry.name) else {
+            continue;
+        };
+        if date < since || archiver.mastodon().status(&id).await?.is_some() {
+            continue;
+        }
+
+        let dir = format!("{}/{}", settings.post_path, entry.name);
+        let markdown_path = match entry.is_dir {
+            true => format!("{dir}/index.md"),
+            false => dir.to_owned(),
+        };
+        let Some(markdown) = gh.get_text(&markdown_path).await? else {
+            continue;
+        };
+
+        match deleted {
+            Deleted::Draft if markdown::is_draft(&markdown) => continue,
+            Deleted::Draft => {
+                changes.push(github::NewContent::text(
+                    &markdown_path,
+                    &markdown::with_draft(&markdown),
+                ));
+            }
+            // A bundle directory holds the post and its media, and nothing else
+            Deleted::Delete if entry.is_dir => {
+                for file in gh.list_dir(&dir).await? {
+                    changes.push(github::NewContent::deletion(&format!(
+                        "{dir}/{}",
+                        file.name
+                    )));
+                }
+            }
+            Deleted::Delete => {
+                changes.push(github::NewContent::deletion(&markdown_path));
+                // Media stored by content may be used by other posts, and media in a bucket aren't in the repository
+                if settings.media_layout == MediaLayout::ContentHash
+                    || settings.media_storage == MediaStorage::S3
+                {
+                    info!("leaving media for {markdown_path} in place");
+                } else {
+                    // Paths from --media-prefix or --media-regex rules may not start with /
+                    let prefix = settings.media_path_prefix.trim_end_matches('/');
+                    for path in markdown::media_paths(&markdown) {
+                        let path = path.trim_start_matches('/');
+                        changes.push(github::NewContent::deletion(&format!("{prefix}/{path}")));
+                    }
+                }
+            }
+        }
+
+        gone.push((markdown_path, id));
+    }
+
+    let committed = confirm && !gone.is_empty();
+    if committed {
+        let message = match deleted {
+            Deleted::Draft => format!("draft {} deleted toot(s)", gone.len()),
+            Deleted::Delete => format!("remove {} deleted toot(s)", gone.len()),
+        };
+        gh.commit(&message, &changes).await?;
+    }
+    Ok(Outcome::Reconciled(Reconciliation {
+        since,
+        gone,
+        committed,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::{Path as UrlPath, State};
+    use axum::routing::{get, post};
+    use axum::{Json, Router};
+    use base64::{engine::general_purpose, Engine};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::future::IntoFuture;
+    use std::sync::{Arc, Mutex};
+    use tempfile::TempDir;
+    use tokio::net::TcpListener;
+
+    const LAST_GUID: &str = "static/mastodon.green/id.txt";
+
+    // The files on the branch, and the headline of each commit
+    #[derive(Default)]
+    struct Repo {
+        files: HashMap<String, String>,
+        commits: Vec<String>,
+    }
+
+    type Shared = Arc<Mutex<Repo>>;
+
+    // Just enough of Github's GraphQL API for a sync: reading files, and committing
+    async fn graphql(State(repo): State<Shared>, Json(request): Json<Value>) -> Json<Value> {
+        let query = request["query"].as_str().unwrap();
+        let variables = &request["variables"];
+        let mut repo = repo.lock().unwrap();
+
+        if query.contains("createCommitOnBranch") {
+            let input = &variables["input"];
+            for addition in input["fileChanges"]["additions"].as_array().unwrap() {
+                let contents = general_purpose::STANDARD
+                    .decode(addition["contents"].as_str().unwrap())
+                    .unwrap();
+                repo.files.insert(
+                    addition["path"].as_str().unwrap().to_owned(),
+                    String::from_utf8(contents).unwrap(),
+                );
+            }
+            repo.commits
+                .push(input["message"]["headline"].as_str().unwrap().to_owned());
+            let url = format!("https://github.com/d6y/blog/commit/{}", repo.commits.len());
+            return Json(
+                json!({ "data": { "createCommitOnBranch": { "commit": { "url": url } } } }),
+            );
+        }
+
+        let path = variables["expr"]
+            .as_str()
+            .unwrap()
+            .trim_start_matches("main:");
+        let object = match repo.files.get(path) {
+            Some(text) => json!({ "text": text, "oid": "abc" }),
+            None => Value::Null,
+        };
+        Json(json!({ "data": { "viewer": { "repository": { "object": object } } } }))
+    }
+
+    async fn head() -> Json<Value> {
+        Json(json!({ "object": { "sha": "abc" } }))
+    }
+
+    // A thread of three toots, each replying to the one before
+    fn toot(n: u64) -> Value {
+        let in_reply_to = (n > 1).then(|| format!("11004130536691200{}", n - 1));
+        json!({
+            "id": format!("11004130536691200{n}"),
+            "url": format!("https://mastodon.green/@d6y/11004130536691200{n}"),
+            "created_at": format!("2023-03-17T18:0{n}:00.000Z"),
+            "in_reply_to_id": in_reply_to,
+            "account": { "id": "1", "acct": "d6y" },
+            "content": format!("<p>Part {n}</p>"),
+            "spoiler_text": if n == 2 { "Spoilers" } else { "" },
+        })
+    }
+
+    async fn status(UrlPath(id): UrlPath<String>) -> Json<Value> {
+        let n = id.chars().last().unwrap().to_digit(10).unwrap();
+        Json(toot(n as u64))
+    }
+
+    async fn context() -> Json<Value> {
+        let thread: Vec<Value> = (1..=3).map(toot).collect();
+        Json(json!({ "ancestors": thread, "descendants": thread }))
+    }
+
+    fn feed() -> String {
+        let items: String = (1..=3)
+            .map(|n| {
+                format!(
+                    r#"<item>
+      <guid isPermaLink="true">https://mastodon.green/@d6y/11004130536691200{n}</guid>
+      <link>https://mastodon.green/@d6y/11004130536691200{n}</link>
+      <pubDate>Fri, 17 Mar 2023 18:0{n}:00 +0000</pubDate>
+      <description>&lt;p&gt;Part {n}&lt;/p&gt;</description>
+    </item>"#
+                )
+            })
+            .collect();
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><rss version="2.0"><channel><title>d6y</title><link>https://mastodon.green/@d6y</link><description>Toots</description>{items}</channel></rss>"#
+        )
+    }
+
+    // Github and the instance, both served locally, with the feed in a file. Returns the URL of the server.
+    async fn serve(repo: &Shared, dir: &TempDir) -> String {
+        repo.lock().unwrap().files.insert(
+            LAST_GUID.to_string(),
+            "https://mastodon.green/@d6y/110041305366912000".to_string(),
+        );
+        std::fs::write(dir.path().join("feed.rss"), feed()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/graphql", post(graphql))
+            .route("/repos/:owner/:repo/git/ref/heads/:branch", get(head))
+            .route("/api/v1/statuses/:id", get(status))
+            .route("/api/v1/statuses/:id/context", get(context))
+            .with_state(repo.clone());
+        tokio::spawn(axum::serve(listener, app).into_future());
+        api
+    }
+
+    #[tokio::test]
+    async fn test_sync_thread_twice() {
+        let repo: Shared = Arc::default();
+        let dir = TempDir::new().unwrap();
+        let api = serve(&repo, &dir).await;
+        let settings = Settings::builder("token", "d6y/blog")
+            .feed_file(&dir.path().join("feed.rss"))
+            .merge_threads(true)
+            .build()
+            .unwrap();
+        let stopping = AtomicBool::new(false);
+
+        // Each run starts afresh, as separate runs of mdmd do, and archives one post
+        for _ in 0..2 {
+            let mut archiver = Archiver::new(&settings).unwrap().with_apis(&api, &api);
+            run_with(&mut archiver, &Command::Sync, &stopping)
+                .await
+                .unwrap();
+        }
+
+        let repo = repo.lock().unwrap();
+        assert_eq!(
+            vec![
+                "add 2023-03-17-toot-110041305366912001.md",
+                "skip to https://mastodon.green/@d6y/110041305366912003"
+            ],
+            repo.commits
+        );
+        assert_eq!(
+            "https://mastodon.green/@d6y/110041305366912003",
+            repo.files[LAST_GUID]
+        );
+        let thread = &repo.files["content/microposts/2023-03-17-toot-110041305366912001.md"];
+        assert!(thread.contains("<p>Part 1</p>") && thread.contains("<p>Part 3</p>"));
+    }
+
+    #[tokio::test]
+    async fn test_skip_thread_with_content_warning() {
+        let repo: Shared = Arc::default();
+        let dir = TempDir::new().unwrap();
+        let api = serve(&repo, &dir).await;
+        let settings = Settings::builder("token", "d6y/blog")
+            .feed_file(&dir.path().join("feed.rss"))
+            .merge_threads(true)
+            .skip_content_warnings(true)
+            .num_posts(3)
+            .build()
+            .unwrap();
+
+        // Only the second part has a warning, which the feed doesn't show for the first
+        let mut archiver = Archiver::new(&settings).unwrap().with_apis(&api, &api);
+        let outcome = run_with(&mut archiver, &Command::Sync, &AtomicBool::new(false))
+            .await
+            .unwrap();
+
+        assert_eq!(0, outcome.changed());
+        assert_eq!(
+            vec!["skip to https://mastodon.green/@d6y/110041305366912003"],
+            repo.lock().unwrap().commits
+        );
+    }
+}
@@ -1,106 +1,207 @@
-use clap::Parser;
-use download::MediaCopy;
+use clap::{error::ErrorKind, CommandFactory, Parser};
 use log::info;
-use rss::Channel;
-use std::{error::Error, str::FromStr};
-use tempfile::TempDir;
-
-use crate::feed::ChannelSurf;
-
-mod download;
-mod feed;
-mod github;
-mod markdown;
-use markdown::AsMarkdown;
-
-#[derive(Parser, Debug)]
-struct Args {
-    /// RSS feed to check
-    #[arg(short, long, default_value = "http://mastodon.green/@d6y.rss")]
-    feed: String,
-
-    /// Where to find the "last id" as a path in Git. Note git paths are rooted in "" so no leading /
-    #[arg(long, default_value = "static/mastodon.green/id.txt")]
-    last_guid_git_path: String,
-
-    /// Media path prefix for writing images. Note git paths are rooted in "" so no leading /
-    #[arg(short, long, default_value = "static")]
-    media_path_prefix: String,
-
-    /// Post path prefix for markdown files. Note git paths are rooted in "" so no leading /
-    #[arg(short, long, default_value = "content/microposts")]
-    post_path: String,
-
-    /// Instance name, which is added into the markdown header
-    #[arg(short, long, default_value = "mastodon.green")]
-    instance: String,
-
-    /// Github bearer token
-    #[arg(long, env = "GITHUB_TOKEN", hide_env_values = true)]
-    pub github_token: String,
-
-    /// Github repository in the form "user/repo"
-    #[arg(long, env = "GITHUB_REPO")]
-    pub github_repo: String,
-
-    /// Github repository branch
-    #[arg(long, env = "GITHUB_BRANCH", default_value = "main")]
-    pub github_branch: String,
-
-    /// Number of posts to read per run
-    #[arg(long, short, env = "NUM_POSTS", default_value = "1")]
-    pub num_posts: usize,
-}
+use mdmd::{metrics, run, Outcome};
+use std::{
+    error::Error,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use crate::cli::{Args, Command, Run};
+
+mod cli;
+mod config;
+mod watch;
+mod webhook;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
-    let args = Args::parse();
-
-    // If you want to try this with a static RSS file on disk...
-    // let rss_str = include_str!("../rss/example01.rss");
-    let rss_str = download::feed(&args.feed).await?;
+    let runs = parse_args()?;
 
-    let channel = Channel::from_str(&rss_str).unwrap();
-
-    let gh = github::Github::new(&args.github_token, &args.github_repo, &args.github_branch);
-    let from = gh.get_last_guid(&args.last_guid_git_path).await?;
-
-    let working_dir = TempDir::new().expect("creating temporary directory");
+    if let Some(Command::Watch {
+        interval,
+        jitter,
+        listen,
+        debounce,
+    }) = runs[0].args.command
+    {
+        return watch::watch(&runs, interval, jitter, listen, debounce).await;
+    }
 
-    for guid in channel.find_next_guids(&from).iter().take(args.num_posts) {
-        // Locate the basic post details:
-        let item = channel.find_by_guid(guid).unwrap();
-        let id = item.link().and_then(|url| url.split('/').last()).unwrap();
+    // Metrics cover the whole process, so come from the first profile
+    let metrics_file = runs[0].args.metrics_file.clone();
+
+    if runs.len() == 1 {
+        let Run { args, settings, .. } = &runs[0];
+        let command = args.library_command().unwrap_or_default();
+        let result = run(settings, &command).await;
+        if let Some(path) = &metrics_file {
+            metrics::write(path)?;
+        }
+        show(args, &result?);
+        return Ok(());
+    }
 
-        // Prepare the markdown:
-        let filename = markdown::post_filename(item.pub_date().unwrap(), id)?;
-        let markdown_path = format!("{}/{filename}", &args.post_path);
+    // Each profile is run in isolation, so one failing doesn't stop the others
+    let mut failed = 0;
+    let mut summary = Vec::new();
+    for Run {
+        name,
+        args,
+        settings,
+    } in &runs
+    {
+        info!("running profile {name}");
+        let command = args.library_command().unwrap_or_default();
+        match run(settings, &command).await {
+            Ok(outcome) => {
+                show(args, &outcome);
+                summary.push(format!("{name}: {} post(s)", outcome.changed()));
+            }
+            Err(err) => {
+                failed += 1;
+                summary.push(format!("{name}: failed: {err}"));
+            }
+        }
+    }
 
-        // Fetch any media, and `truncate_media_url` which is a hack to convert remote image URLs to local paths.
-        let media_map = item.download_all(working_dir.path()).await?;
-        let markdown = item.as_markdown(markdown::truncate_media_url, &args.instance)?;
-        let path_map = media_map
-            .apply(markdown::truncate_media_url)
-            .apply(|u| format!("{}{u}", &args.media_path_prefix));
+    for line in summary {
+        println!("{line}");
+    }
 
-        // Convert into Github new content, which is a path in the repo and the content (as text or a path on disk)
-        let mut new_content: Vec<github::NewContent> = path_map
-            .into_iter()
-            .map(|(path, file)| github::NewContent::path(path, file))
-            .collect();
+    if let Some(path) = &metrics_file {
+        metrics::write(path)?;
+    }
 
-        let md_content = github::NewContent::text(&markdown_path, &markdown);
-        new_content.push(md_content);
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{failed} of {} profiles failed", runs.len()).into()),
+    }
+}
 
-        // ...updating the next GUID file is also a "new content":
-        let id_content = github::NewContent::text(&args.last_guid_git_path, guid.value());
-        new_content.push(id_content);
+// Print what a command found, for those which are run to find something out
+fn show(args: &Args, outcome: &Outcome) {
+    match outcome {
+        Outcome::Changed(_) => {}
+        Outcome::Status(status) => {
+            println!("feed: {}", status.feed);
+            println!("last guid: {}", status.last_guid);
+            println!("pending: {} post(s)", status.pending);
+            match &status.last_commit {
+                Some(commit) => println!(
+                    "last commit: {} ({}) {}",
+                    commit.headline, commit.date, commit.url
+                ),
+                None => println!("last commit: none on {}", args.github_branch),
+            }
+        }
+        Outcome::Rendered(markdown) => print!("{markdown}"),
+        Outcome::Reconciled(reconciliation) => {
+            for (markdown_path, id) in &reconciliation.gone {
+                println!("{markdown_path}: toot {id} no longer exists");
+            }
+            let gone = reconciliation.gone.len();
+            if gone == 0 {
+                println!("no deleted toots found since {}", reconciliation.since);
+            } else if !reconciliation.committed {
+                println!("dry run: use --confirm to commit changes to {gone} post(s)");
+            }
+        }
+    }
+}
 
-        info!("{filename}");
-        gh.commit(&format!("add {filename}"), &new_content).await?;
+// The runs to make: just the command line, or one for each profile in the config file
+fn parse_args() -> Result<Vec<Run>, Box<dyn Error>> {
+    let cli: Vec<OsString> = std::env::args_os().collect();
+
+    // Just enough parsing to find the config file, as required arguments may be in there
+    let early = Args::command()
+        .ignore_errors(true)
+        .get_matches_from(cli.iter());
+
+    let config = early.get_one::<PathBuf>("config");
+    let profiles: Vec<Option<&String>> = match early.get_many::<String>("profile") {
+        Some(profiles) => profiles.map(Some).collect(),
+        None => vec![None],
+    };
+
+    let mut runs: Vec<Run> = Vec::new();
+    for profile in profiles {
+        let args = match config {
+            None => Args::parse_from(cli.iter()),
+            Some(config) => {
+                let text = std::fs::read_to_string(config)
+                    .map_err(|e| format!("Unable to read {}: {e}", config.display()))?;
+                let settings = config::profile_args(&text, profile.map(|p| p.as_str()), &|name| {
+                    std::env::var(name).ok()
+                })?;
+                Args::parse_from(config::with_profile(
+                    &Args::command(),
+                    cli.clone(),
+                    &settings,
+                )?)
+            }
+        };
+
+        // Stdin can only be read once
+        if args.reads_stdin() && args.library_command().is_none() {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--feed-file - can't be used with watch",
+                )
+                .exit();
+        }
+
+        if args.prints() && args.report.as_deref() == Some(Path::new("-")) {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--report - can't be used with status, render, or reconcile, which print to stdout",
+                )
+                .exit();
+        }
+
+        let settings = match args.settings() {
+            Ok(settings) => settings,
+            Err(message) => Args::command()
+                .error(ErrorKind::ArgumentConflict, message)
+                .exit(),
+        };
+
+        // Two profiles archiving to the same place would fight over the last GUID
+        if let Some(other) = runs.iter().find(|run| run.args.same_archive(&args)) {
+            let message = format!(
+                "profiles {} and {} both archive to {} on {} of {}",
+                other.name,
+                profile.map(|p| p.as_str()).unwrap_or_default(),
+                args.last_guid_git_path,
+                args.github_branch,
+                args.github_repo
+            );
+            Args::command()
+                .error(ErrorKind::ArgumentConflict, message)
+                .exit();
+        }
+
+        let name = profile.cloned().unwrap_or_else(|| args.feed.clone());
+        runs.push(Run {
+            name,
+            args,
+            settings,
+        });
     }
 
-    Ok(())
+    // ...so only one profile can read it
+    if runs.len() > 1 && runs.iter().any(|run| run.args.reads_stdin()) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--feed-file - can't be used with more than one profile",
+            )
+            .exit();
+    }
+    Ok(runs)
 }
@@ -1,32 +1,237 @@
-use chrono::{DateTime, ParseError};
+use chrono::{DateTime, NaiveDate, ParseError};
+use clap::ValueEnum;
+use regex::Regex;
 use rss::{extension::Extension, Item};
+use std::sync::LazyLock;
 
-use crate::feed::ItemSurf;
+use crate::feed::{ItemSurf, MediaSurf};
+use crate::images::ImageDetails;
 
+/// Where a media file can be found on the blog, plus any resized copies as `(width, path)`,
+/// and the image dimensions if we've measured them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaRef {
+    pub path: String,
+    pub variants: Vec<(u32, String)>,
+    pub details: Option<ImageDetails>,
+}
+
+impl From<String> for MediaRef {
+    fn from(path: String) -> MediaRef {
+        MediaRef {
+            path,
+            variants: Vec::new(),
+            details: None,
+        }
+    }
+}
+
+/// How to write out images
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ImageMarkup {
+    /// Plain markdown: ![description](path)
+    #[default]
+    Markdown,
+    /// An HTML <img> with a srcset of any resized variants
+    Srcset,
+    /// A Hugo figure shortcode
+    Figure,
+}
+
+/// How to show a post that has a content warning
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ContentWarnings {
+    /// Hide the post in a <details> block, with the warning as the summary
+    #[default]
+    Details,
+    /// Show the post as normal. The warning is only in the front matter
+    Inline,
+}
+
+/// How to show media marked as sensitive
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SensitiveMedia {
+    /// Hide each sensitive media item in a <details> block, so it is only shown when clicked
+    #[default]
+    Reveal,
+    /// Show sensitive media like any other media
+    Inline,
+    /// Leave sensitive media out of the archive altogether
+    Exclude,
+}
+
+/// What to say about the post a reply is replying to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ReplyContext {
+    /// Nothing
+    #[default]
+    None,
+    /// The URL and account of the post, in the front matter
+    Metadata,
+    /// As for metadata, plus a quoted excerpt of the post above the reply
+    Quote,
+}
+
+/// The post a reply is replying to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplyTo {
+    pub url: String,
+    pub account: String, // e.g., @user@example.org
+    pub excerpt: Option<String>,
+}
+
+/// Settings which change the markdown we produce
+#[derive(Debug, Clone)]
+pub struct MarkdownOptions {
+    pub instance: String,
+    pub image_markup: ImageMarkup,
+    pub content_warnings: ContentWarnings,
+    pub sensitive_media: SensitiveMedia,
+}
+
+impl MarkdownOptions {
+    /// The default options, for posts from `instance`
+    pub fn new(instance: &str) -> MarkdownOptions {
+        MarkdownOptions {
+            instance: instance.to_owned(),
+            image_markup: ImageMarkup::default(),
+            content_warnings: ContentWarnings::default(),
+            sensitive_media: SensitiveMedia::default(),
+        }
+    }
+}
+
+/// Posts, or threads of posts, which can be written as markdown for the blog
 pub trait AsMarkdown {
-    fn as_markdown<F>(&self, media_url_to_path: F, instance: &str) -> Result<String, ParseError>
+    /// Render as markdown, saying which post this replies to when `reply_to` is given
+    fn as_markdown<F, R>(
+        &self,
+        media_url_to_path: F,
+        options: &MarkdownOptions,
+        reply_to: Option<&ReplyTo>,
+    ) -> Result<String, ParseError>
     where
-        F: Fn(&str) -> String;
+        F: Fn(&str) -> R,
+        R: Into<MediaRef>;
 }
 
 impl AsMarkdown for Item {
-    fn as_markdown<F: Fn(&str) -> String>(
+    fn as_markdown<F: Fn(&str) -> R, R: Into<MediaRef>>(
         &self,
         media_url_to_path: F,
-        instance: &str,
+        options: &MarkdownOptions,
+        reply_to: Option<&ReplyTo>,
     ) -> Result<String, ParseError> {
-        let title = title_date(self.pub_date().unwrap())?;
-        let msg = self.description().unwrap_or("");
-        let url = self.link().unwrap();
-        let date = formal_date(self.pub_date().unwrap())?;
-
-        let mut markdown_medias = Vec::new();
-        for media in self.medias() {
-            let markdown_media = to_markdown_media(media, &media_url_to_path);
-            markdown_medias.push(markdown_media);
+        std::slice::from_ref(self).as_markdown(media_url_to_path, options, reply_to)
+    }
+}
+
+/// A thread of posts is written as a single post: the details come from the first part,
+/// and each part is followed by its own media.
+impl AsMarkdown for [Item] {
+    fn as_markdown<F: Fn(&str) -> R, R: Into<MediaRef>>(
+        &self,
+        media_url_to_path: F,
+        options: &MarkdownOptions,
+        reply_to: Option<&ReplyTo>,
+    ) -> Result<String, ParseError> {
+        let first = self.first().expect("a post has at least one part");
+        let instance = &options.instance;
+        let title = title_date(first.pub_date().unwrap())?;
+        let content_warning = first.content_warning();
+        let url = first.link().unwrap();
+        let date = formal_date(first.pub_date().unwrap())?;
+
+        // Optional front matter, one line per entry
+        let mut extra = Vec::new();
+
+        if let Some(warning) = &content_warning {
+            extra.push(format!(
+                "content_warning: {}",
+                yaml_string(&unescape_html(warning))
+            ));
         }
 
-        let images = markdown_medias.join("\n");
+        if let Some(reply_to) = reply_to {
+            extra.push(format!("in_reply_to: {}", yaml_string(&reply_to.url)));
+            extra.push(format!(
+                "in_reply_to_account: {}",
+                yaml_string(&reply_to.account)
+            ));
+        }
+
+        let mut parts = Vec::new();
+        let mut image_details = Vec::new();
+        for (n, item) in self.iter().enumerate() {
+            let msg = item.body();
+            let mut markdown_medias = Vec::new();
+            for media in item.medias() {
+                let sensitive = media.is_sensitive();
+                if sensitive && options.sensitive_media == SensitiveMedia::Exclude {
+                    continue;
+                }
+                let media_url = media.attrs.get("url").unwrap();
+                let media_ref: MediaRef = media_url_to_path(media_url).into();
+                let markdown_media = to_markdown_media(media, &media_ref, options.image_markup);
+                let markdown_media = match sensitive && options.sensitive_media == SensitiveMedia::Reveal {
+                    true => format!("<details class='sensitive'><summary>Sensitive media</summary>\n\n{markdown_media}\n</details>\n"),
+                    false => markdown_media,
+                };
+                markdown_medias.push(markdown_media);
+                if let Some(details) = &media_ref.details {
+                    image_details.push(images_entry(&media_ref.path, details));
+                }
+            }
+            let images = markdown_medias.join("\n");
+            let part = format!("{msg}\n\n{images}");
+
+            // Each part keeps its own warning. The first part's is also in the front matter, so inline it needs no more.
+            let part = match (item.content_warning(), options.content_warnings) {
+                (Some(warning), ContentWarnings::Details) => {
+                    format!("<details><summary>{warning}</summary>\n\n{part}\n</details>\n")
+                }
+                (Some(warning), ContentWarnings::Inline) if n > 0 => {
+                    format!("<p><strong>Content warning:</strong> {warning}</p>\n\n{part}")
+                }
+                _ => part,
+            };
+            parts.push(part);
+        }
+
+        if self
+            .iter()
+            .flat_map(|item| item.medias())
+            .any(|m| m.is_sensitive())
+        {
+            extra.push("sensitive: true".to_string());
+        }
+
+        if self.len() > 1 {
+            extra.push("thread:".to_string());
+            for item in self {
+                extra.push(format!("  - {}", item.link().unwrap()));
+            }
+        }
+
+        if !image_details.is_empty() {
+            extra.push("images:".to_string());
+            extra.append(&mut image_details);
+        }
+
+        let extra: String = extra.iter().map(|line| format!("{line}\n")).collect();
+
+        let body = parts.join("\n\n");
+
+        // The post being replied to comes first, marked up as an IndieWeb citation
+        let body = match reply_to.and_then(|r| r.excerpt.as_ref().map(|e| (r, e))) {
+            Some((reply_to, excerpt)) => format!(
+                "<blockquote class='u-in-reply-to h-cite'><p><a class='u-url p-author' href='{}'>{}</a>: <span class='p-content'>{}</span></p></blockquote>\n\n{body}",
+                escape_attr(&reply_to.url),
+                escape_attr(&reply_to.account),
+                escape_attr(excerpt)
+            ),
+            None => body,
+        };
 
         Ok(format!(
             r#"---
@@ -34,19 +239,126 @@ title: {title}
 instance: {instance}
 toot_url: {url}
 date: {date}
----
-
-{msg}
+{extra}---
 
-{images}"#
+{body}"#
         ))
     }
 }
 
-fn to_markdown_media<F: Fn(&str) -> String>(media: &Extension, media_url_to_path: F) -> String {
+/// Add a `content_hash:` to the front matter of the markdown, so we can tell later if the post has changed.
+/// An `updated:` date is added too when the markdown replaces an earlier version.
+pub fn with_content_hash(markdown: &str, hash: &str, updated: Option<&str>) -> String {
+    let updated = updated
+        .map(|date| format!("updated: {date}\n"))
+        .unwrap_or_default();
+    match split_front_matter(markdown) {
+        Some((front_matter, rest)) => {
+            format!("---\n{front_matter}content_hash: {hash}\n{updated}{rest}")
+        }
+        None => markdown.to_owned(),
+    }
+}
+
+/// The hash recorded in markdown we archived earlier, if any.
+/// Posts archived before we recorded hashes have none.
+pub fn archived_content_hash(markdown: &str) -> Option<String> {
+    split_front_matter(markdown)
+        .and_then(|(front_matter, _)| {
+            front_matter
+                .lines()
+                .find_map(|line| line.strip_prefix("content_hash: "))
+        })
+        .map(|hash| hash.trim().to_owned())
+}
+
+/// Is the markdown a Hugo draft, which isn't published?
+pub fn is_draft(markdown: &str) -> bool {
+    split_front_matter(markdown)
+        .map(|(front_matter, _)| {
+            front_matter
+                .lines()
+                .any(|line| line.trim() == "draft: true")
+        })
+        .unwrap_or(false)
+}
+
+/// Mark the markdown as a Hugo draft, so it's no longer published, replacing any `draft:` it already has
+pub fn with_draft(markdown: &str) -> String {
+    match split_front_matter(markdown) {
+        Some((front_matter, rest)) => {
+            let front_matter: String = front_matter
+                .lines()
+                .filter(|line| !line.starts_with("draft:"))
+                .map(|line| format!("{line}\n"))
+                .collect();
+            format!("---\n{front_matter}draft: true\n{rest}")
+        }
+        None => markdown.to_owned(),
+    }
+}
+
+/// The paths of media the markdown refers to, as the media markup writes them: `src` and `srcset` attributes,
+/// and markdown images and links on lines of their own. Links to other sites are left out.
+pub fn media_paths(markdown: &str) -> Vec<String> {
+    static ATTRIBUTE: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r#"\b(src|srcset)=(?:'([^']*)'|"([^"]*)")"#).unwrap());
+    static LINK: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"^!?\[.*\]\(([^)\s]+)\)$").unwrap());
+
+    let body = split_front_matter(markdown)
+        .map(|(_, rest)| rest)
+        .unwrap_or(markdown);
+    let mut found: Vec<&str> = Vec::new();
+    for line in body.lines() {
+        if let Some(link) = LINK.captures(line.trim()) {
+            found.push(link.get(1).map_or("", |path| path.as_str()));
+        }
+        for attribute in ATTRIBUTE.captures_iter(line) {
+            let value = attribute
+                .get(2)
+                .or(attribute.get(3))
+                .map_or("", |value| value.as_str());
+            match &attribute[1] {
+                // Candidates are written as "<path> <width>w", separated by commas
+                "srcset" => found.extend(
+                    value
+                        .split(',')
+                        .filter_map(|candidate| candidate.split_whitespace().next()),
+                ),
+                _ => found.push(value),
+            }
+        }
+    }
+
+    let mut paths: Vec<String> = Vec::new();
+    for path in found {
+        let is_local = !path.is_empty() && !path.contains("://") && !path.starts_with('#');
+        if is_local && !paths.iter().any(|p| p == path) {
+            paths.push(path.to_owned());
+        }
+    }
+    paths
+}
+
+// Split markdown into the front matter lines (between the --- lines), and the rest from the closing --- onwards
+fn split_front_matter(markdown: &str) -> Option<(&str, &str)> {
+    let inside = markdown.strip_prefix("---\n")?;
+    let end = inside.find("\n---\n")? + 1;
+    Some((&inside[..end], &inside[end..]))
+}
+
+// An entry in the `images:` front matter list
+fn images_entry(path: &str, details: &ImageDetails) -> String {
+    format!(
+        "  - src: {path}\n    width: {}\n    height: {}\n    blurhash: \"{}\"\n    colour: \"{}\"",
+        details.width, details.height, details.blurhash, details.colour
+    )
+}
+
+fn to_markdown_media(media: &Extension, media_ref: &MediaRef, markup: ImageMarkup) -> String {
     let media_type = media.attrs.get("type").unwrap();
-    let media_url = media.attrs.get("url").unwrap();
-    let media_path = media_url_to_path(media_url);
+    let media_path = &media_ref.path;
     let media_description = media
         .children
         .get("description")
@@ -54,13 +366,78 @@ fn to_markdown_media<F: Fn(&str) -> String>(media: &Extension, media_url_to_path
         .unwrap_or("".to_owned());
 
     if media_type.starts_with("video/") {
-        // RSS does not appear to include media width or height, so we just pick a reasonable height here
-        format!("<video height='720' controls=''><source src='{media_path}' type='{media_type}'><p>{media_description}</p></video>")
+        // RSS does not include the size of a video, so the browser works it out
+        format!("<video controls=''><source src='{media_path}' type='{media_type}'><p>{media_description}</p></video>")
+    } else if media_type.starts_with("audio/") {
+        format!("<audio controls=''><source src='{media_path}' type='{media_type}'><p>{media_description}</p></audio>\n")
+    } else if media_type.starts_with("image/") {
+        let alt = escape_attr(&media_description);
+        let details = media_ref.details.as_ref();
+        let srcset = match (markup, media_ref.variants.is_empty()) {
+            (ImageMarkup::Srcset, false) => {
+                let candidates: Vec<String> = media_ref
+                    .variants
+                    .iter()
+                    .map(|(width, path)| format!("{path} {width}w"))
+                    .collect();
+                format!(" srcset='{}' sizes='100vw'", candidates.join(", "))
+            }
+            _ => String::new(),
+        };
+        match markup {
+            ImageMarkup::Figure => {
+                let size = details
+                    .map(|d| format!(" width=\"{}\" height=\"{}\"", d.width, d.height))
+                    .unwrap_or_default();
+                format!("{{{{< figure src=\"{media_path}\" alt=\"{alt}\"{size} >}}}}\n")
+            }
+            // Markdown has no way to give an image a size, so with one we use HTML
+            _ if !srcset.is_empty() || details.is_some() => {
+                // Width and height let the browser reserve space before the image loads
+                let size = details
+                    .map(|d| format!(" width='{}' height='{}'", d.width, d.height))
+                    .unwrap_or_default();
+                format!("<img src='{media_path}'{srcset}{size} alt='{alt}'>\n")
+            }
+            _ => format!("![{media_description}]({media_path})\n"),
+        }
     } else {
-        format!("![{media_description}]({media_path})\n")
+        // Something we don't know how to display (e.g., application/pdf), so link to it
+        let text = match media_description.is_empty() {
+            true => media_path
+                .rsplit('/')
+                .next()
+                .unwrap_or(media_path)
+                .to_owned(),
+            false => media_description,
+        };
+        format!("[{text}]({media_path})\n")
     }
 }
 
+// A double-quoted YAML string, which is safe whatever the text contains
+fn yaml_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// Turn the text in HTML back into plain text, for the entities Mastodon uses
+pub(crate) fn unescape_html(html: &str) -> String {
+    html.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+// Make text safe to use inside a quoted HTML attribute, or as HTML text
+pub(crate) fn escape_attr(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
 fn title_date(pub_date: &str) -> Result<String, ParseError> {
     let title_format = "%a %d %b %Y %H:%M"; // Tue 12 Dec 2006 11:02
     DateTime::parse_from_rfc2822(pub_date).map(|dt| dt.format(title_format).to_string())
@@ -70,11 +447,28 @@ fn formal_date(pub_date: &str) -> Result<String, ParseError> {
     DateTime::parse_from_rfc2822(pub_date).map(|dt| dt.to_rfc3339())
 }
 
+/// The name of the markdown file for a post, e.g., 2023-02-04-toot-1234.md
 pub fn post_filename(pub_date: &str, id: &str) -> Result<String, ParseError> {
+    post_slug(pub_date, id).map(|slug| format!("{slug}.md"))
+}
+
+/// The name of a post without an extension, which is also the directory name for a page bundle
+pub fn post_slug(pub_date: &str, id: &str) -> Result<String, ParseError> {
     let filename_format = "%Y-%m-%d"; // 2005-12-30
     let filename_date =
         DateTime::parse_from_rfc2822(pub_date).map(|dt| dt.format(filename_format).to_string())?;
-    Ok(format!("{filename_date}-toot-{id}.md"))
+    Ok(format!("{filename_date}-toot-{id}"))
+}
+
+/// The date and toot id from a post file name (2023-02-04-toot-1234.md) or bundle directory (2023-02-04-toot-1234)
+pub fn parse_post_slug(name: &str) -> Option<(NaiveDate, String)> {
+    let slug = name.strip_suffix(".md").unwrap_or(name);
+    let (date, id) = slug.split_once("-toot-")?;
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()?;
+    match !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()) {
+        true => Some((date, id.to_owned())),
+        false => None,
+    }
 }
 
 /// This is a hack to convert remote (instance-side) media URLs into local paths
@@ -89,6 +483,7 @@ mod tests {
     use pretty_assertions::assert_eq;
 
     use super::*;
+    use crate::download;
     use crate::feed::ChannelSurf;
     use rss::{Channel, Guid};
     use std::str::FromStr;
@@ -111,6 +506,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_post_slug() {
+        let expected = Some((
+            NaiveDate::from_ymd_opt(2023, 2, 4).unwrap(),
+            "1234".to_string(),
+        ));
+        assert_eq!(expected, parse_post_slug("2023-02-04-toot-1234.md"));
+        assert_eq!(expected, parse_post_slug("2023-02-04-toot-1234"));
+        assert_eq!(None, parse_post_slug("_index.md"));
+        assert_eq!(None, parse_post_slug("2023-02-04-toot-.md"));
+    }
+
     #[test]
     fn test_convert_item_with_images_to_markdown() {
         let channel = Channel::from_str(RSS_STR).unwrap();
@@ -140,9 +547,400 @@ The success of an ASM work is often determined by the relationship a viewer has
 
         assert_eq!(
             expected,
-            item.as_markdown(|x| x.to_string(), "mastodon.green")
+            item.as_markdown(
+                |x| x.to_string(),
+                &MarkdownOptions::new("mastodon.green"),
+                None
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_convert_image_with_variants_to_srcset() {
+        let channel = Channel::from_str(RSS_STR).unwrap();
+
+        let from: Guid = Guid {
+            value: "https://mastodon.green/@d6y/109848262470543120".to_string(),
+            permalink: true,
+        };
+
+        let media = channel.find_by_guid(&from).unwrap().medias()[1];
+
+        let media_ref = MediaRef {
+            path: "/a.jpeg".to_string(),
+            variants: vec![
+                (480, "/a-480w.webp".to_string()),
+                (960, "/a-960w.webp".to_string()),
+            ],
+            details: None,
+        };
+
+        let expected = "<img src='/a.jpeg' srcset='/a-480w.webp 480w, /a-960w.webp 960w' sizes='100vw' alt='screenshot of asking ChatGPT to create a Red Dwarf themed adventure game. It prevented me from taking the clothes of a hologram.'>\n";
+        assert_eq!(
+            expected,
+            to_markdown_media(media, &media_ref, ImageMarkup::Srcset)
+        );
+
+        let expected = "{{< figure src=\"/a.jpeg\" alt=\"screenshot of asking ChatGPT to create a Red Dwarf themed adventure game. It prevented me from taking the clothes of a hologram.\" >}}\n";
+        assert_eq!(
+            expected,
+            to_markdown_media(media, &media_ref, ImageMarkup::Figure)
+        );
+    }
+
+    #[test]
+    fn test_convert_item_with_image_details_to_markdown() {
+        let channel = Channel::from_str(RSS_STR).unwrap();
+
+        let from: Guid = Guid {
+            value: "https://mastodon.green/@d6y/109848262470543120".to_string(),
+            permalink: true,
+        };
+
+        let item = channel.find_by_guid(&from).unwrap();
+
+        let media_ref = |url: &str| MediaRef {
+            path: truncate_media_url(url),
+            variants: vec![],
+            details: Some(ImageDetails {
+                width: 1200,
+                height: 800,
+                blurhash: "LEHV6nWB2yk8pyo0adR*.7kCMdnj".to_string(),
+                colour: "#336699".to_string(),
+            }),
+        };
+
+        let options = MarkdownOptions {
+            image_markup: ImageMarkup::Srcset,
+            ..MarkdownOptions::new("mastodon.green")
+        };
+
+        let expected = r##"---
+title: Sat 11 Feb 2023 21:37
+instance: mastodon.green
+toot_url: https://mastodon.green/@d6y/109848262470543120
+date: 2023-02-11T21:37:45+00:00
+images:
+  - src: /mastodon.green/media_attachments/files/109/848/241/370/274/576/original/c7374d6d7c7ca1ce.jpeg
+    width: 1200
+    height: 800
+    blurhash: "LEHV6nWB2yk8pyo0adR*.7kCMdnj"
+    colour: "#336699"
+  - src: /mastodon.green/media_attachments/files/109/848/241/629/959/036/original/16d94c2b2a06a51d.jpeg
+    width: 1200
+    height: 800
+    blurhash: "LEHV6nWB2yk8pyo0adR*.7kCMdnj"
+    colour: "#336699"
+---
+
+<p>Does this count as “making my own entertainment”? (The adventure game was disappointing)</p>
+
+<img src='/mastodon.green/media_attachments/files/109/848/241/370/274/576/original/c7374d6d7c7ca1ce.jpeg' width='1200' height='800' alt='screenshots from asking ChatGPT what would happen if C-3PO was replaced with Alien. The answer includes “the Alien would likely bring a different, more aggressive energy to the group”, which I think we can agree with.'>
+
+<img src='/mastodon.green/media_attachments/files/109/848/241/629/959/036/original/16d94c2b2a06a51d.jpeg' width='1200' height='800' alt='screenshot of asking ChatGPT to create a Red Dwarf themed adventure game. It prevented me from taking the clothes of a hologram.'>
+"##;
+
+        assert_eq!(
+            expected,
+            item.as_markdown(media_ref, &options, None).unwrap()
+        );
+
+        // Markdown can't say how big an image is, so the default markup uses the same <img>
+        let options = MarkdownOptions::new("mastodon.green");
+        assert_eq!(
+            expected,
+            item.as_markdown(media_ref, &options, None).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_convert_item_with_content_warning_to_markdown() {
+        let channel = Channel::from_str(include_str!("../rss/example02.rss")).unwrap();
+        let item = &channel.items()[0];
+
+        let expected = r#"---
+title: Fri 17 Mar 2023 18:02
+instance: mastodon.green
+toot_url: https://mastodon.green/@d6y/110041305366912345
+date: 2023-03-17T18:02:11+00:00
+content_warning: "Spoilers for \"The Last of Us\""
+---
+
+<details><summary>Spoilers for &quot;The Last of Us&quot;</summary>
+
+<p>That ending, though.</p>
+
+
+</details>
+"#;
+
+        assert_eq!(
+            expected,
+            item.as_markdown(
+                |x| x.to_string(),
+                &MarkdownOptions::new("mastodon.green"),
+                None
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_convert_thread_to_markdown() {
+        let channel = Channel::from_str(include_str!("../rss/example02.rss")).unwrap();
+        let thread = [channel.items()[1].clone(), channel.items()[0].clone()];
+
+        let options = MarkdownOptions {
+            content_warnings: ContentWarnings::Inline,
+            sensitive_media: SensitiveMedia::Inline,
+            ..MarkdownOptions::new("mastodon.green")
+        };
+        let markdown = thread
+            .as_markdown(|_| "/a.jpeg".to_string(), &options, None)
+            .unwrap();
+
+        let expected_body = r#"content_warning: "Medical"
+sensitive: true
+thread:
+  - https://mastodon.green/@d6y/110041297012345678
+  - https://mastodon.green/@d6y/110041305366912345
+---
+
+<p>Stitches out today.</p>
+
+![A healing scar on a knee](/a.jpeg)
+
+
+<p><strong>Content warning:</strong> Spoilers for &quot;The Last of Us&quot;</p>
+
+<p>That ending, though.</p>
+
+"#;
+        assert!(markdown.starts_with("---\ntitle: Fri 17 Mar 2023 18:00\n"));
+        assert!(markdown.ends_with(expected_body));
+
+        // Each part is behind its own warning
+        let options = MarkdownOptions {
+            content_warnings: ContentWarnings::Details,
+            ..options
+        };
+        let markdown = thread
+            .as_markdown(|_| "/a.jpeg".to_string(), &options, None)
+            .unwrap();
+        let expected_body = r#"<details><summary>Medical</summary>
+
+<p>Stitches out today.</p>
+
+![A healing scar on a knee](/a.jpeg)
+
+</details>
+
+
+<details><summary>Spoilers for &quot;The Last of Us&quot;</summary>
+
+<p>That ending, though.</p>
+
+
+</details>
+"#;
+        assert!(markdown.ends_with(expected_body));
+    }
+
+    #[test]
+    fn test_convert_item_with_sensitive_media_to_markdown() {
+        let channel = Channel::from_str(include_str!("../rss/example02.rss")).unwrap();
+        let item = &channel.items()[1];
+
+        let options = MarkdownOptions {
+            content_warnings: ContentWarnings::Inline,
+            ..MarkdownOptions::new("mastodon.green")
+        };
+
+        let expected = r#"---
+title: Fri 17 Mar 2023 18:00
+instance: mastodon.green
+toot_url: https://mastodon.green/@d6y/110041297012345678
+date: 2023-03-17T18:00:03+00:00
+content_warning: "Medical"
+sensitive: true
+---
+
+<p>Stitches out today.</p>
+
+<details class='sensitive'><summary>Sensitive media</summary>
+
+![A healing scar on a knee](/a.jpeg)
+
+</details>
+"#;
+        assert_eq!(
+            expected,
+            item.as_markdown(|_| "/a.jpeg".to_string(), &options, None)
                 .unwrap()
         );
+
+        let options = MarkdownOptions {
+            sensitive_media: SensitiveMedia::Exclude,
+            ..options
+        };
+        let markdown = item
+            .as_markdown(|_| "/a.jpeg".to_string(), &options, None)
+            .unwrap();
+        assert!(markdown.contains("sensitive: true"));
+        assert!(!markdown.contains("/a.jpeg"));
+    }
+
+    #[test]
+    fn test_convert_reply_to_markdown() {
+        let channel = Channel::from_str(include_str!("../rss/example02.rss")).unwrap();
+        let item = &channel.items()[0];
+
+        let options = MarkdownOptions {
+            content_warnings: ContentWarnings::Inline,
+            ..MarkdownOptions::new("mastodon.green")
+        };
+        let reply_to = ReplyTo {
+            url: "https://example.org/@someone/1".to_string(),
+            account: "@someone@example.org".to_string(),
+            excerpt: Some("What did you think of \"The Last of Us\"?".to_string()),
+        };
+
+        let expected = r#"content_warning: "Spoilers for \"The Last of Us\""
+in_reply_to: "https://example.org/@someone/1"
+in_reply_to_account: "@someone@example.org"
+---
+
+<blockquote class='u-in-reply-to h-cite'><p><a class='u-url p-author' href='https://example.org/@someone/1'>@someone@example.org</a>: <span class='p-content'>What did you think of &quot;The Last of Us&quot;?</span></p></blockquote>
+
+<p>That ending, though.</p>
+
+"#;
+        let markdown = item
+            .as_markdown(|url| url.to_string(), &options, Some(&reply_to))
+            .unwrap();
+        assert!(markdown.ends_with(expected));
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let markdown = "---\ntitle: Hello\n---\n\n<p>Hello</p>\n\n";
+        let hash = download::digest(markdown.as_bytes());
+
+        let archived = with_content_hash(markdown, &hash, None);
+        assert_eq!(
+            format!("---\ntitle: Hello\ncontent_hash: {hash}\n---\n\n<p>Hello</p>\n\n"),
+            archived
+        );
+        assert_eq!(Some(hash.clone()), archived_content_hash(&archived));
+
+        let updated = with_content_hash(markdown, &hash, Some("2023-03-18T09:00:00+00:00"));
+        assert!(updated.contains("\nupdated: 2023-03-18T09:00:00+00:00\n---\n"));
+        assert_eq!(Some(hash), archived_content_hash(&updated));
+
+        // Without a hash in the front matter, there's nothing to compare with
+        assert_eq!(None, archived_content_hash(markdown));
+    }
+
+    #[test]
+    fn test_draft() {
+        let markdown = "---\ntitle: Hello\n---\n\n<p>Hello</p>\n";
+        assert!(!is_draft(markdown));
+
+        let draft = with_draft(markdown);
+        assert_eq!(
+            "---\ntitle: Hello\ndraft: true\n---\n\n<p>Hello</p>\n",
+            draft
+        );
+        assert!(is_draft(&draft));
+
+        // A post that was a draft already, or was published again, has its draft setting replaced
+        let published = "---\ntitle: Hello\ndraft: false\ndate: 2023-03-17\n---\n\n<p>Hello</p>\n";
+        let draft = with_draft(published);
+        assert_eq!(
+            "---\ntitle: Hello\ndate: 2023-03-17\ndraft: true\n---\n\n<p>Hello</p>\n",
+            draft
+        );
+        assert_eq!(draft, with_draft(&draft));
+    }
+
+    #[test]
+    fn test_media_paths() {
+        let markdown = r#"---
+images:
+  - src: /not/in/body.jpeg
+---
+
+<p>Hello, /not a path, and [not](/a link either)</p>
+
+![A [with brackets]](/a.jpeg)
+
+<img src='/b.jpeg' srcset='/b-480w.webp 480w, /b-960w.webp 960w' sizes='100vw' alt='B'>
+
+<video controls=''><source src='/c.mp4' type='video/mp4'><p></p></video>
+[elsewhere](https://example.org/d.pdf)
+{{< figure src="media/e.png" alt="Written by a --media-prefix rule" >}}
+[f.pdf](/f.pdf)
+"#;
+        assert_eq!(
+            vec![
+                "/a.jpeg",
+                "/b.jpeg",
+                "/b-480w.webp",
+                "/b-960w.webp",
+                "/c.mp4",
+                "media/e.png",
+                "/f.pdf"
+            ],
+            media_paths(markdown)
+        );
+    }
+
+    fn media_of_type(media_type: &str, description: &str) -> Extension {
+        let mut media = Extension::default();
+        media
+            .attrs
+            .insert("type".to_string(), media_type.to_string());
+        media.attrs.insert(
+            "url".to_string(),
+            "https://files.example.org/a/b".to_string(),
+        );
+        if !description.is_empty() {
+            let child = Extension {
+                value: Some(description.to_string()),
+                ..Default::default()
+            };
+            media
+                .children
+                .insert("description".to_string(), vec![child]);
+        }
+        media
+    }
+
+    #[test]
+    fn test_convert_audio_and_other_media() {
+        let media_ref = MediaRef::from("/a/talk.mp3".to_string());
+
+        let audio = media_of_type("audio/mpeg", "A short talk");
+        let expected = "<audio controls=''><source src='/a/talk.mp3' type='audio/mpeg'><p>A short talk</p></audio>\n";
+        assert_eq!(
+            expected,
+            to_markdown_media(&audio, &media_ref, ImageMarkup::Markdown)
+        );
+
+        let media_ref = MediaRef::from("/a/notes.pdf".to_string());
+
+        let described = media_of_type("application/pdf", "Meeting notes");
+        assert_eq!(
+            "[Meeting notes](/a/notes.pdf)\n",
+            to_markdown_media(&described, &media_ref, ImageMarkup::Markdown)
+        );
+
+        let undescribed = media_of_type("application/pdf", "");
+        assert_eq!(
+            "[notes.pdf](/a/notes.pdf)\n",
+            to_markdown_media(&undescribed, &media_ref, ImageMarkup::Markdown)
+        );
     }
 
     #[test]
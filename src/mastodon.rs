@@ -0,0 +1,417 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::LazyLock;
+
+use chrono::DateTime;
+use regex::Regex;
+use reqwest::{Client, StatusCode};
+use rss::extension::Extension;
+use rss::{Guid, Item};
+use serde::Deserialize;
+
+use crate::markdown::{escape_attr, unescape_html, ReplyTo};
+use crate::media;
+
+/// The public (unauthenticated) Mastodon API of an instance, for details the RSS feed doesn't include
+pub struct Mastodon {
+    instance: String, // e.g., mastodon.green
+    api: String,      // e.g., https://mastodon.green
+    client: Client,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Status {
+    pub id: String,
+    pub url: Option<String>,
+    pub created_at: String,
+    pub in_reply_to_id: Option<String>,
+    pub account: Account,
+    pub content: String,
+    #[serde(default)]
+    pub spoiler_text: String,
+    #[serde(default)]
+    pub sensitive: bool,
+    #[serde(default)]
+    pub media_attachments: Vec<Attachment>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Account {
+    pub id: String,
+    pub acct: String, // user for local accounts, user@host for others
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Attachment {
+    #[serde(rename = "type")]
+    pub kind: String, // image, gifv, video, audio or unknown
+    pub url: String,
+    pub description: Option<String>,
+}
+
+/// The posts before and after a status in its conversation
+#[derive(Deserialize, Debug)]
+pub struct Context {
+    pub ancestors: Vec<Status>,
+    pub descendants: Vec<Status>,
+}
+
+impl Mastodon {
+    pub fn new(instance: &str) -> Mastodon {
+        Mastodon {
+            instance: instance.to_owned(),
+            api: format!("https://{instance}"),
+            client: Client::new(),
+        }
+    }
+
+    // For tests, which stand in for the instance with a local server
+    #[cfg(test)]
+    pub(crate) fn with_api(self, api: &str) -> Mastodon {
+        Mastodon {
+            api: api.to_owned(),
+            ..self
+        }
+    }
+
+    /// A status by id. `None` means the instance doesn't have it (e.g., it has been deleted).
+    pub async fn status(&self, id: &str) -> Result<Option<Status>, Box<dyn Error>> {
+        let url = format!("{}/api/v1/statuses/{id}", self.api);
+        let res = self.client.get(&url).send().await?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Ok(Some(res.error_for_status()?.json().await?)),
+        }
+    }
+
+    pub async fn context(&self, id: &str) -> Result<Context, Box<dyn Error>> {
+        let url = format!("{}/api/v1/statuses/{id}/context", self.api);
+        let res = self.client.get(&url).send().await?;
+        Ok(res.error_for_status()?.json().await?)
+    }
+
+    /// All the parts of the self-thread that `status` belongs to, oldest first.
+    /// A status which isn't part of a thread is returned on its own.
+    pub async fn self_thread(&self, status: Status) -> Result<Vec<Status>, Box<dyn Error>> {
+        let context = self.context(&status.id).await?;
+        Ok(self_thread(status, &context))
+    }
+
+    /// The post `status` replies to, if any. Replies to posts we can't see (deleted, or not public) have no context.
+    /// The excerpt of the post is only included when `excerpt` is true.
+    pub async fn reply_to(
+        &self,
+        status: &Status,
+        excerpt: bool,
+    ) -> Result<Option<ReplyTo>, Box<dyn Error>> {
+        let Some(parent_id) = &status.in_reply_to_id else {
+            return Ok(None);
+        };
+        let Some(parent) = self.status(parent_id).await? else {
+            return Ok(None);
+        };
+        Ok(Some(parent.as_reply_to(&self.instance, excerpt)))
+    }
+}
+
+/// Does the URL look like a toot on the instance, e.g. https://mastodon.green/@d6y/110041305366912345?
+pub fn is_status_url(instance: &str, url: &str) -> bool {
+    let pattern = format!(r"^https?://{}/@[^/]+/\d+$", regex::escape(instance));
+    Regex::new(&pattern).is_ok_and(|status_url| status_url.is_match(url))
+}
+
+/// The chain of posts by the same author that `status` is part of: up through the posts it replies to,
+/// and down through the first reply to itself at each step.
+pub fn self_thread(status: Status, context: &Context) -> Vec<Status> {
+    let author = status.account.id.to_owned();
+    let by_id = |id: &str| {
+        context
+            .ancestors
+            .iter()
+            .chain(context.descendants.iter())
+            .find(|s| s.id == id)
+    };
+
+    let mut earlier = Vec::new();
+    let mut parent_id = status.in_reply_to_id.to_owned();
+    while let Some(parent) = parent_id.as_deref().and_then(by_id) {
+        if parent.account.id != author {
+            break;
+        }
+        earlier.push(parent.to_owned());
+        parent_id = parent.in_reply_to_id.to_owned();
+    }
+    earlier.reverse();
+
+    let mut thread = earlier;
+    thread.push(status);
+    while let Some(reply) = context.descendants.iter().find(|s| {
+        s.account.id == author && s.in_reply_to_id.as_ref() == thread.last().map(|t| &t.id)
+    }) {
+        thread.push(reply.to_owned());
+    }
+    thread
+}
+
+impl Status {
+    // What a reply needs to say about this status, which is the post being replied to
+    fn as_reply_to(&self, instance: &str, excerpt: bool) -> ReplyTo {
+        let account = match self.account.acct.contains('@') {
+            true => format!("@{}", self.account.acct),
+            false => format!("@{}@{instance}", self.account.acct),
+        };
+        // Don't quote what's behind a content warning
+        let excerpt = excerpt.then(|| match self.spoiler_text.is_empty() {
+            true => plain_text_excerpt(&self.content, EXCERPT_LENGTH),
+            false => format!("Content warning: {}", self.spoiler_text),
+        });
+        ReplyTo {
+            url: self.url.to_owned().unwrap_or_default(),
+            account,
+            excerpt,
+        }
+    }
+
+    /// Present a status as the RSS item Mastodon would have put in a feed, so it can be archived in the same way
+    pub fn to_item(&self) -> Item {
+        let url = self.url.to_owned().unwrap_or_default();
+
+        let description = match self.spoiler_text.is_empty() {
+            true => self.content.to_owned(),
+            false => format!(
+                "<p><strong>Content warning:</strong> {}</p><hr />{}",
+                escape_attr(&self.spoiler_text),
+                self.content
+            ),
+        };
+
+        let medias: Vec<Extension> = self
+            .media_attachments
+            .iter()
+            .map(|media| media_extension(media, self.sensitive))
+            .collect();
+        let mut extensions = BTreeMap::new();
+        if !medias.is_empty() {
+            extensions.insert(
+                "media".to_string(),
+                BTreeMap::from([("content".to_string(), medias)]),
+            );
+        }
+
+        Item {
+            link: Some(url.to_owned()),
+            guid: Some(Guid {
+                value: url,
+                permalink: true,
+            }),
+            pub_date: DateTime::parse_from_rfc3339(&self.created_at)
+                .ok()
+                .map(|dt| dt.to_rfc2822()),
+            description: Some(description),
+            extensions,
+            ..Default::default()
+        }
+    }
+}
+
+// The equivalent of a <media:content> element in the feed
+fn media_extension(media: &Attachment, sensitive: bool) -> Extension {
+    let child = |name: &str, value: &str| {
+        (
+            name.to_string(),
+            vec![Extension {
+                name: format!("media:{name}"),
+                value: Some(value.to_owned()),
+                ..Default::default()
+            }],
+        )
+    };
+
+    let rating = match sensitive {
+        true => "adult",
+        false => "nonadult",
+    };
+    let mut children = BTreeMap::from([child("rating", rating)]);
+    if let Some(description) = &media.description {
+        children.extend([child("description", description)]);
+    }
+
+    Extension {
+        name: "media:content".to_string(),
+        value: None,
+        attrs: BTreeMap::from([
+            ("url".to_string(), media.url.to_owned()),
+            (
+                "type".to_string(),
+                media::attachment_type(&media.kind, &media.url).to_string(),
+            ),
+        ]),
+        children,
+    }
+}
+
+// The number of characters we quote from a post being replied to
+const EXCERPT_LENGTH: usize = 200;
+
+// The text of some HTML, shortened to at most `length` characters, breaking between words
+fn plain_text_excerpt(html: &str, length: usize) -> String {
+    let paragraphs = html.replace("</p><p>", " ").replace("<br />", " ");
+    static TAGS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+    let text = TAGS.replace_all(&paragraphs, "");
+    let text = unescape_html(&text);
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    if text.chars().count() <= length {
+        return text;
+    }
+    let mut excerpt = String::new();
+    for word in text.split(' ') {
+        if excerpt.chars().count() + word.chars().count() + 1 > length {
+            break;
+        }
+        if !excerpt.is_empty() {
+            excerpt.push(' ');
+        }
+        excerpt.push_str(word);
+    }
+    format!("{excerpt}…")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::feed::{ItemSurf, MediaSurf};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_is_status_url() {
+        let url = "https://mastodon.green/@d6y/110041305366912345";
+        assert!(is_status_url("mastodon.green", url));
+        assert!(!is_status_url("fosstodon.org", url));
+        assert!(!is_status_url("mastodon.green", "110041305366912345"));
+        assert!(!is_status_url(
+            "mastodon.green",
+            "https://mastodon.green/@d6y/110041305366912345/edit"
+        ));
+    }
+
+    fn status(id: &str, account: &str, in_reply_to: Option<&str>) -> Status {
+        let json = format!(
+            r#"{{
+                "id": "{id}",
+                "url": "https://mastodon.green/@{account}/{id}",
+                "created_at": "2023-03-17T18:00:03.000Z",
+                "in_reply_to_id": {},
+                "in_reply_to_account_id": null,
+                "account": {{ "id": "{account}", "acct": "{account}" }},
+                "content": "<p>Part {id}</p>",
+                "spoiler_text": "",
+                "sensitive": false,
+                "media_attachments": []
+            }}"#,
+            in_reply_to
+                .map(|id| format!("\"{id}\""))
+                .unwrap_or("null".to_string())
+        );
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn ids(thread: &[Status]) -> Vec<&str> {
+        thread.iter().map(|s| s.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_self_thread() {
+        // someone -> 1 -> 2 -> 3, with a reply from someone else to 2, and a later self-reply to 1
+        let context = Context {
+            ancestors: vec![status("0", "other", None), status("1", "d6y", Some("0"))],
+            descendants: vec![
+                status("3", "d6y", Some("2")),
+                status("4", "other", Some("2")),
+                status("5", "d6y", Some("1")),
+            ],
+        };
+        let thread = self_thread(status("2", "d6y", Some("1")), &context);
+        assert_eq!(vec!["1", "2", "3"], ids(&thread));
+
+        let alone = Context {
+            ancestors: vec![],
+            descendants: vec![],
+        };
+        let thread = self_thread(status("9", "d6y", None), &alone);
+        assert_eq!(vec!["9"], ids(&thread));
+    }
+
+    #[test]
+    fn test_reply_to() {
+        let mut parent = status("1", "someone@example.org", None);
+        parent.content =
+            "<p>Does anyone know a good <a href=\"https://example.org\">book</a> on &quot;Rust&quot;?</p><p>Thanks!</p>"
+                .to_string();
+
+        let reply_to = parent.as_reply_to("mastodon.green", true);
+        assert_eq!(
+            "https://mastodon.green/@someone@example.org/1",
+            reply_to.url
+        );
+        assert_eq!("@someone@example.org", reply_to.account);
+        assert_eq!(
+            Some("Does anyone know a good book on \"Rust\"? Thanks!".to_string()),
+            reply_to.excerpt
+        );
+
+        let local = status("2", "d6y", None).as_reply_to("mastodon.green", false);
+        assert_eq!("@d6y@mastodon.green", local.account);
+        assert_eq!(None, local.excerpt);
+    }
+
+    #[test]
+    fn test_plain_text_excerpt() {
+        assert_eq!(
+            "The quick brown…",
+            plain_text_excerpt("<p>The quick brown fox</p>", 18)
+        );
+    }
+
+    #[test]
+    fn test_to_item() {
+        let json = r#"{
+            "id": "110041297012345678",
+            "url": "https://mastodon.green/@d6y/110041297012345678",
+            "created_at": "2023-03-17T18:00:03.000Z",
+            "in_reply_to_id": null,
+            "in_reply_to_account_id": null,
+            "account": { "id": "1", "acct": "d6y" },
+            "content": "<p>Stitches out today.</p>",
+            "spoiler_text": "Medical",
+            "sensitive": true,
+            "media_attachments": [{
+                "id": "2", "type": "image",
+                "url": "https://files.mastodon.green/media_attachments/files/110/041/296/123/456/789/original/5b1f0a3cc1d2e3f4.jpeg",
+                "description": "A healing scar on a knee"
+            }]
+        }"#;
+        let status: Status = serde_json::from_str(json).unwrap();
+        let item = status.to_item();
+
+        assert_eq!(Some("Fri, 17 Mar 2023 18:00:03 +0000"), item.pub_date());
+        assert_eq!(Some("Medical".to_string()), item.content_warning());
+        assert_eq!("<p>Stitches out today.</p>", item.body());
+        let medias = item.medias();
+        assert_eq!("image/jpeg", medias[0].attrs["type"]);
+        assert!(medias[0].is_sensitive());
+    }
+
+    #[test]
+    fn test_attachment_type_from_the_api() {
+        let json = r#"{
+            "id": "2", "type": "audio",
+            "url": "https://files.mastodon.green/media_attachments/files/110/041/296/123/456/790/original/voice.m4a",
+            "description": null
+        }"#;
+        let attachment: Attachment = serde_json::from_str(json).unwrap();
+        let media = media_extension(&attachment, false);
+
+        assert_eq!("audio/mp4", media.attrs["type"]);
+    }
+}
@@ -0,0 +1,72 @@
+use clap::ValueEnum;
+
+/// Where media files are published
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum MediaStorage {
+    /// Commit media files to the Github repository, alongside the markdown
+    #[default]
+    Git,
+    /// Commit media files as Git LFS pointers, with the content uploaded to Github's LFS storage
+    Lfs,
+    /// Upload media files to an S3-compatible bucket, and link to them from the markdown
+    S3,
+}
+
+// The MIME type of a media file by its extension, e.g., for serving media straight from a bucket
+pub(crate) fn content_type(key: &str) -> &'static str {
+    let ext = key.rsplit('.').next().unwrap_or("").to_lowercase();
+    match ext.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "m4a" => "audio/mp4",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        _ => "application/octet-stream",
+    }
+}
+
+// The MIME type of a Mastodon attachment, given its `type` ("image", "gifv", "video", "audio", "unknown").
+// The extension gives the subtype when it agrees; otherwise we fall back to what Mastodon transcodes to.
+pub(crate) fn attachment_type(kind: &str, url: &str) -> &'static str {
+    let by_extension = content_type(url);
+    let (prefix, fallback) = match kind {
+        "image" => ("image/", "image/jpeg"),
+        "gifv" | "video" => ("video/", "video/mp4"),
+        "audio" => ("audio/", "audio/mpeg"),
+        _ => return by_extension,
+    };
+    match by_extension.starts_with(prefix) {
+        true => by_extension,
+        false => fallback,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attachment_type() {
+        assert_eq!("image/png", attachment_type("image", "https://a.b/c.png"));
+        assert_eq!(
+            "video/quicktime",
+            attachment_type("video", "https://a.b/c.MOV")
+        );
+        assert_eq!("video/mp4", attachment_type("gifv", "https://a.b/c.mp4"));
+        assert_eq!("audio/mp4", attachment_type("audio", "https://a.b/c.m4a"));
+        assert_eq!("audio/mpeg", attachment_type("audio", "https://a.b/c.opus"));
+        assert_eq!("video/mp4", attachment_type("video", "https://a.b/c"));
+        assert_eq!(
+            "image/jpeg",
+            attachment_type("unknown", "https://a.b/c.jpg")
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use regex::Regex;
+
+use crate::download::LocalMedia;
+use crate::markdown::truncate_media_url;
+
+/// The overall scheme for naming media files on the blog
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum MediaLayout {
+    /// Derive the path from the media URL, using any rules we've been given
+    #[default]
+    Url,
+    /// media/<post-id>/<n>.<ext>, where n counts from 1 for each post
+    Flat,
+    /// media/<sha256>.<ext>, so the same file attached to several posts is only stored once
+    ContentHash,
+}
+
+/// A way to rewrite a remote media URL into a path on the blog
+#[derive(Debug, Clone)]
+pub enum Rule {
+    /// Replace a leading `from` with `to`
+    Prefix { from: String, to: String },
+    /// Replace a match of `from`, where `to` can refer to capture groups as $1, $2, etc.
+    Regex { from: Regex, to: String },
+}
+
+impl Rule {
+    /// Parse a prefix rule written as FROM=TO, e.g., https://cdn.example.org/=/example.org/
+    pub fn prefix(spec: &str) -> Result<Rule, String> {
+        let (from, to) = split_rule(spec)?;
+        Ok(Rule::Prefix {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        })
+    }
+
+    /// Parse a regex rule written as PATTERN=REPLACEMENT, e.g., ^https://[^/]+/system/(.*)$=/system/$1
+    pub fn regex(spec: &str) -> Result<Rule, String> {
+        let (from, to) = split_rule(spec)?;
+        let from = Regex::new(from).map_err(|e| e.to_string())?;
+        Ok(Rule::Regex {
+            from,
+            to: to.to_owned(),
+        })
+    }
+
+    fn apply(&self, url: &str) -> Option<String> {
+        match self {
+            Rule::Prefix { from, to } => url
+                .strip_prefix(from.as_str())
+                .map(|rest| format!("{to}{rest}")),
+            Rule::Regex { from, to } => from
+                .is_match(url)
+                .then(|| from.replace(url, to.as_str()).into_owned()),
+        }
+    }
+}
+
+// Rules are written as FROM=TO, splitting at the first =
+fn split_rule(spec: &str) -> Result<(&str, &str), String> {
+    spec.split_once('=')
+        .ok_or_else(|| format!("expected FROM=TO but found: {spec}"))
+}
+
+/// Turns media URLs into blog paths. The same mapping is used for the links in the markdown
+/// and for where files are committed, so they always agree.
+#[derive(Debug, Clone, Default)]
+pub struct MediaPaths {
+    layout: MediaLayout,
+    rules: Vec<Rule>,
+    bundle: bool,
+}
+
+impl MediaPaths {
+    pub fn new(layout: MediaLayout, rules: &[Rule]) -> MediaPaths {
+        MediaPaths {
+            layout,
+            rules: rules.to_owned(),
+            bundle: false,
+        }
+    }
+
+    /// For Hugo page bundles, media sit in the same directory as the post's index.md,
+    /// so paths are just a file name, relative to the post.
+    pub fn with_bundle(self, bundle: bool) -> MediaPaths {
+        MediaPaths { bundle, ..self }
+    }
+
+    pub fn layout(&self) -> MediaLayout {
+        self.layout
+    }
+
+    /// The blog path (with a leading /, unless bundled) for a media URL attached to the post `post_id`
+    pub fn path(&self, url: &str, post_id: &str, media: &LocalMedia) -> String {
+        let path = self.site_path(url, post_id, media);
+        if !self.bundle {
+            return path;
+        }
+        // Files from different places can share a name, so names from URLs are numbered in the order they're attached
+        let name = path.rsplit('/').next().unwrap_or(&path);
+        match (self.layout, media.index_of(url)) {
+            (MediaLayout::Url, Some(index)) => format!("{}-{name}", index + 1),
+            _ => name.to_owned(),
+        }
+    }
+
+    fn site_path(&self, url: &str, post_id: &str, media: &LocalMedia) -> String {
+        let by_layout = match self.layout {
+            MediaLayout::Url => None,
+            MediaLayout::Flat => media.index_of(url).map(|index| match extension(url) {
+                Some(ext) => format!("/media/{post_id}/{}.{ext}", index + 1),
+                None => format!("/media/{post_id}/{}", index + 1),
+            }),
+            MediaLayout::ContentHash => media.content_addressed_url(url),
+        };
+
+        by_layout
+            .or_else(|| self.rules.iter().find_map(|rule| rule.apply(url)))
+            .unwrap_or_else(|| fallback(url))
+    }
+}
+
+// Without a rule, we keep the existing behaviour for "files." hosts, and otherwise
+// use the host and path of the URL: https://cdn.example.org/a/b.png becomes /cdn.example.org/a/b.png
+fn fallback(url: &str) -> String {
+    let url = without_query(url);
+    if url.starts_with("https://files.") {
+        return truncate_media_url(url);
+    }
+    match url.split_once("://") {
+        Some((_, rest)) => format!("/{rest}"),
+        None => url.to_owned(),
+    }
+}
+
+fn without_query(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
+}
+
+/// The file extension of a URL, leaving out any query string or fragment
+pub(crate) fn extension(url: &str) -> Option<&str> {
+    Path::new(without_query(url))
+        .extension()
+        .and_then(|ext| ext.to_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const URL: &str = "https://files.mastodon.green/media_attachments/files/109/848/241/370/274/576/original/c7374d6d7c7ca1ce.jpeg";
+
+    #[test]
+    fn test_default_paths() {
+        let paths = MediaPaths::default();
+        let media = LocalMedia::new();
+
+        assert_eq!(
+            "/mastodon.green/media_attachments/files/109/848/241/370/274/576/original/c7374d6d7c7ca1ce.jpeg",
+            paths.path(URL, "1", &media)
+        );
+        assert_eq!(
+            "/cdn.example.org/system/a/b.png",
+            paths.path("https://cdn.example.org/system/a/b.png?v=2", "1", &media)
+        );
+    }
+
+    #[test]
+    fn test_rules_in_order() {
+        let rules = vec![
+            Rule::prefix("https://cdn.example.org/=/example.org/").unwrap(),
+            Rule::regex(r"^https://[^/]+\.s3\.amazonaws\.com/(.*)$=/s3/$1").unwrap(),
+            Rule::prefix("https://cdn.example.org/system/=/unused/").unwrap(),
+        ];
+        let paths = MediaPaths::new(MediaLayout::Url, &rules);
+        let media = LocalMedia::new();
+
+        assert_eq!(
+            "/example.org/system/a/b.png",
+            paths.path("https://cdn.example.org/system/a/b.png", "1", &media)
+        );
+        assert_eq!(
+            "/s3/cache/a.mp4",
+            paths.path("https://bucket.s3.amazonaws.com/cache/a.mp4", "1", &media)
+        );
+    }
+
+    #[test]
+    fn test_flat_paths() {
+        let paths = MediaPaths::new(MediaLayout::Flat, &[]);
+        let mut media = LocalMedia::new();
+        media.push("https://example.org/a.jpeg", Path::new("/tmp/a.jpeg"), "");
+        media.push("https://example.org/b.mp4?x=1", Path::new("/tmp/b.mp4"), "");
+
+        assert_eq!(
+            "/media/1234/2.mp4",
+            paths.path("https://example.org/b.mp4?x=1", "1234", &media)
+        );
+    }
+
+    #[test]
+    fn test_bundle_paths() {
+        let paths = MediaPaths::default().with_bundle(true);
+        let media = LocalMedia::new();
+
+        assert_eq!("c7374d6d7c7ca1ce.jpeg", paths.path(URL, "1", &media));
+
+        // Two files with the same name, from different places
+        let other = "https://cdn.example.org/c7374d6d7c7ca1ce.jpeg";
+        let mut media = LocalMedia::new();
+        media.push(URL, Path::new("/tmp/1-c7374d6d7c7ca1ce.jpeg"), "abc");
+        media.push(other, Path::new("/tmp/2-c7374d6d7c7ca1ce.jpeg"), "def");
+        assert_eq!("1-c7374d6d7c7ca1ce.jpeg", paths.path(URL, "1", &media));
+        assert_eq!("2-c7374d6d7c7ca1ce.jpeg", paths.path(other, "1", &media));
+
+        let flat = MediaPaths::new(MediaLayout::Flat, &[]).with_bundle(true);
+        assert_eq!("2.jpeg", flat.path(other, "1", &media));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(Rule::prefix("no-equals-sign").is_err());
+        assert!(Rule::regex("(unclosed=/x").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Name, type, and help text of each metric, in the order they are shown
+const METRICS: &[(&str, &str, &str)] = &[
+    (
+        "mdmd_posts_published_total",
+        "counter",
+        "Posts committed to the repository, new or updated",
+    ),
+    (
+        "mdmd_media_downloaded_bytes_total",
+        "counter",
+        "Bytes of media downloaded from the instance",
+    ),
+    (
+        "mdmd_media_uploaded_bytes_total",
+        "counter",
+        "Bytes of media published to the repository, Git LFS, or a bucket",
+    ),
+    (
+        "mdmd_api_errors_total",
+        "counter",
+        "Failed calls to the Github API, by endpoint",
+    ),
+    (
+        "mdmd_feed_fetch_seconds",
+        "summary",
+        "Time taken to fetch the feed",
+    ),
+    (
+        "mdmd_newest_archived_toot_timestamp_seconds",
+        "gauge",
+        "When the newest archived toot was posted",
+    ),
+    (
+        "mdmd_newest_feed_toot_timestamp_seconds",
+        "gauge",
+        "When the newest toot in the feed was posted",
+    ),
+    (
+        "mdmd_archive_lag_seconds",
+        "gauge",
+        "How far the newest archived toot is behind the newest toot in the feed",
+    ),
+];
+
+// Values by sample name and labels, for the whole process: a watch serves them, and a one-off run writes them to a file.
+static VALUES: Mutex<BTreeMap<(String, String), f64>> = Mutex::new(BTreeMap::new());
+
+/// Add to a counter
+pub(crate) fn inc(name: &str, labels: &[(&str, &str)], by: f64) {
+    *VALUES
+        .lock()
+        .unwrap()
+        .entry((name.to_owned(), label_text(labels)))
+        .or_default() += by;
+}
+
+/// Set a gauge
+pub(crate) fn set(name: &str, labels: &[(&str, &str)], value: f64) {
+    VALUES
+        .lock()
+        .unwrap()
+        .insert((name.to_owned(), label_text(labels)), value);
+}
+
+/// Record one observation of a summary, as its `_sum` and `_count`
+pub(crate) fn observe(name: &str, labels: &[(&str, &str)], value: f64) {
+    inc(&format!("{name}_sum"), labels, value);
+    inc(&format!("{name}_count"), labels, 1.0);
+}
+
+/// A failed call to a Github endpoint (e.g., `graphql` or `get_oid`)
+pub(crate) fn api_error(endpoint: &str) {
+    inc("mdmd_api_errors_total", &[("endpoint", endpoint)], 1.0);
+}
+
+/// Every metric recorded so far, in the Prometheus text format
+pub fn render() -> String {
+    let values = VALUES.lock().unwrap();
+    let mut text = String::new();
+    for (name, kind, help) in METRICS {
+        let samples: Vec<_> = values
+            .iter()
+            .filter(|((sample, _), _)| {
+                sample == name
+                    || (*kind == "summary"
+                        && sample
+                            .strip_prefix(name)
+                            .is_some_and(|suffix| suffix == "_sum" || suffix == "_count"))
+            })
+            .collect();
+        if samples.is_empty() {
+            continue;
+        }
+        text.push_str(&format!("# HELP {name} {help}\n# TYPE {name} {kind}\n"));
+        for ((sample, labels), value) in samples {
+            text.push_str(&format!("{sample}{labels} {value}\n"));
+        }
+    }
+    text
+}
+
+/// Write the metrics for node_exporter's textfile collector, replacing the file in one go
+pub fn write(path: &Path) -> Result<(), Box<dyn Error>> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, render())?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+// Labels as `{name="value",...}`, or nothing when there are none
+fn label_text(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{name}=\"{value}\"")
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let feed = [("feed", "https://example.org/@\"quoted\".rss")];
+        inc("mdmd_posts_published_total", &feed, 1.0);
+        inc("mdmd_posts_published_total", &feed, 2.0);
+        observe("mdmd_feed_fetch_seconds", &feed, 0.5);
+        api_error("get_oid");
+
+        let text = render();
+        assert!(text.contains("# TYPE mdmd_posts_published_total counter\n"));
+        assert!(text.contains(
+            "mdmd_posts_published_total{feed=\"https://example.org/@\\\"quoted\\\".rss\"} 3\n"
+        ));
+        assert!(text.contains("# TYPE mdmd_feed_fetch_seconds summary\n"));
+        assert!(text.contains("mdmd_feed_fetch_seconds_count{feed="));
+        assert!(text.contains("mdmd_api_errors_total{endpoint=\"get_oid\"} 1\n"));
+    }
+}
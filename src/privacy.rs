@@ -0,0 +1,239 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+use img_parts::jpeg::markers::APP1;
+use img_parts::webp::{CHUNK_EXIF, CHUNK_XMP};
+use img_parts::{Bytes, DynImage, ImageEXIF};
+
+use crate::download::{self, LocalMedia};
+
+/// Kinds of metadata we remove from images before they are published
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metadata {
+    Exif,
+    Gps,
+    Xmp,
+}
+
+impl fmt::Display for Metadata {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Metadata::Exif => write!(f, "EXIF"),
+            Metadata::Gps => write!(f, "GPS"),
+            Metadata::Xmp => write!(f, "XMP"),
+        }
+    }
+}
+
+/// A record of the metadata removed from one file
+#[derive(Debug)]
+pub struct Stripped {
+    pub url: String,
+    pub removed: Vec<Metadata>,
+}
+
+const XMP_JPEG_PREFIXES: [&[u8]; 2] = [
+    b"http://ns.adobe.com/xap/1.0/\0",
+    b"http://ns.adobe.com/xmp/extension/\0",
+];
+const XMP_PNG_PREFIX: &[u8] = b"XML:com.adobe.xmp\0";
+
+const GPS_TAG: u16 = 0x8825;
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Remove metadata from every downloaded image, rewriting the files in place.
+/// Only files which had something removed appear in the result.
+pub fn strip_all(media: &mut LocalMedia) -> Result<Vec<Stripped>, Box<dyn Error>> {
+    let files: Vec<(String, PathBuf)> = media
+        .into_iter()
+        .map(|(url, local_file)| (url.to_owned(), local_file.to_owned()))
+        .collect();
+    let mut report = Vec::new();
+    for (index, (url, local_file)) in files.into_iter().enumerate() {
+        let bytes = Bytes::from(std::fs::read(&local_file)?);
+
+        if let Some((clean, removed)) = strip(bytes)? {
+            std::fs::write(&local_file, &clean)?;
+            media.set_digest(index, &download::digest(&clean));
+            report.push(Stripped { url, removed });
+        }
+    }
+    Ok(report)
+}
+
+/// Remove EXIF (which is where GPS locations live) and XMP metadata from a JPEG, PNG, or WebP image.
+/// Only the EXIF orientation is kept, as without it a photo taken on its side would be shown on its side.
+/// The result is `None` when there was nothing to remove, or for formats we don't handle (e.g., video).
+pub fn strip(bytes: Bytes) -> Result<Option<(Bytes, Vec<Metadata>)>, img_parts::Error> {
+    let mut image = match DynImage::from_bytes(bytes)? {
+        Some(image) => image,
+        None => return Ok(None),
+    };
+
+    let mut removed = Vec::new();
+
+    let mut orientation = None;
+    if let Some(exif) = exif(&image) {
+        orientation = ifd0_short(&exif, ORIENTATION_TAG).filter(|o| *o != 1);
+        // An EXIF block with only an orientation is just what we would write back
+        let tiff = exif.strip_prefix(b"Exif\0\0").unwrap_or(&exif);
+        if tiff != orientation_exif(orientation.unwrap_or(1)) {
+            removed.push(Metadata::Exif);
+        }
+        if ifd0_short(&exif, GPS_TAG).is_some() {
+            removed.push(Metadata::Gps);
+        }
+    }
+
+    let had_xmp = match &mut image {
+        DynImage::Jpeg(jpeg) => {
+            let segments = jpeg.segments_mut();
+            let before = segments.len();
+            segments.retain(|s| {
+                !(s.marker() == APP1
+                    && XMP_JPEG_PREFIXES
+                        .iter()
+                        .any(|p| s.contents().starts_with(p)))
+            });
+            segments.len() != before
+        }
+        DynImage::Png(png) => {
+            let chunks = png.chunks_mut();
+            let before = chunks.len();
+            chunks.retain(|c| !c.contents().starts_with(XMP_PNG_PREFIX));
+            chunks.len() != before
+        }
+        DynImage::WebP(webp) => {
+            let had_xmp = webp.has_chunk(CHUNK_XMP);
+            webp.remove_chunks_by_id(CHUNK_XMP);
+            had_xmp
+        }
+    };
+    if had_xmp {
+        removed.push(Metadata::Xmp);
+    }
+
+    if removed.is_empty() {
+        return Ok(None);
+    }
+
+    // Also tidies up the WebP header flags, which say which chunks are present
+    image.set_exif(orientation.map(|o| Bytes::from(orientation_exif(o))));
+
+    Ok(Some((image.encoder().bytes(), removed)))
+}
+
+// The EXIF block, if any, in TIFF format
+fn exif(image: &DynImage) -> Option<Bytes> {
+    match image {
+        // A WebP EXIF chunk may or may not start with the "Exif\0\0" that `exif()` expects
+        DynImage::WebP(webp) => webp.chunk_by_id(CHUNK_EXIF)?.content().data().cloned(),
+        _ => image.exif(),
+    }
+}
+
+// EXIF is a TIFF structure. The orientation is a SHORT in the first directory, and a GPS location is a pointer from it.
+// This is the first two bytes of the value of a tag in that directory, which is all of a SHORT.
+fn ifd0_short(exif: &[u8], tag: u16) -> Option<u16> {
+    let tiff = exif.strip_prefix(b"Exif\0\0").unwrap_or(exif);
+
+    let little_endian = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let u16_at = |at: usize| {
+        let bytes: [u8; 2] = tiff.get(at..at + 2)?.try_into().ok()?;
+        Some(match little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    };
+    let u32_at = |at: usize| {
+        let bytes: [u8; 4] = tiff.get(at..at + 4)?.try_into().ok()?;
+        Some(match little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let count = u16_at(ifd)?;
+    (0..count as usize)
+        .map(|entry| ifd + 2 + 12 * entry)
+        .find(|at| u16_at(*at) == Some(tag))
+        .and_then(|at| u16_at(at + 8))
+}
+
+// A little-endian TIFF with one directory entry: the orientation, as a SHORT
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+    let mut tiff = vec![b'I', b'I', 42, 0, 8, 0, 0, 0, 1, 0];
+    tiff.extend(ORIENTATION_TAG.to_le_bytes());
+    tiff.extend([3, 0, 1, 0, 0, 0]); // type SHORT, count 1
+    tiff.extend(orientation.to_le_bytes());
+    tiff.extend([0, 0, 0, 0, 0, 0]); // padding, then no next directory
+    tiff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    // A little-endian TIFF with one directory entry: the GPS pointer
+    const EXIF_WITH_GPS: &[u8] = &[
+        b'I', b'I', 42, 0, 8, 0, 0, 0, // header, first directory at offset 8
+        1, 0, // one entry
+        // tag 0x8825, type LONG, count 1, offset 26; then no next directory
+        0x25, 0x88, 4, 0, 1, 0, 0, 0, 26, 0, 0, 0, 0, 0, 0, 0,
+    ];
+
+    fn jpeg() -> Bytes {
+        let mut buf = Cursor::new(Vec::new());
+        RgbImage::new(8, 8)
+            .write_to(&mut buf, ImageFormat::Jpeg)
+            .unwrap();
+        Bytes::from(buf.into_inner())
+    }
+
+    #[test]
+    fn test_strip_exif_with_gps_from_jpeg() {
+        let mut image = DynImage::from_bytes(jpeg()).unwrap().unwrap();
+        image.set_exif(Some(Bytes::from_static(EXIF_WITH_GPS)));
+        let with_exif = image.encoder().bytes();
+
+        let (clean, removed) = strip(with_exif).unwrap().unwrap();
+
+        assert_eq!(vec![Metadata::Exif, Metadata::Gps], removed);
+        assert_eq!(None, DynImage::from_bytes(clean).unwrap().unwrap().exif());
+    }
+
+    #[test]
+    fn test_keep_orientation() {
+        // Rotated 90° clockwise, with a GPS pointer after it
+        let mut exif = orientation_exif(6);
+        exif[8] = 2;
+        exif.truncate(22);
+        exif.extend([0x25, 0x88, 4, 0, 1, 0, 0, 0, 38, 0, 0, 0, 0, 0, 0, 0]);
+        let mut image = DynImage::from_bytes(jpeg()).unwrap().unwrap();
+        image.set_exif(Some(Bytes::from(exif)));
+
+        let (clean, removed) = strip(image.encoder().bytes()).unwrap().unwrap();
+
+        assert_eq!(vec![Metadata::Exif, Metadata::Gps], removed);
+        let exif = DynImage::from_bytes(clean.clone()).unwrap().unwrap().exif();
+        assert_eq!(Some(6), ifd0_short(&exif.unwrap(), ORIENTATION_TAG));
+        // ...and there's nothing more to remove
+        assert!(strip(clean).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_nothing_to_strip() {
+        assert!(strip(jpeg()).unwrap().is_none());
+        assert!(strip(Bytes::from_static(b"not an image"))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,139 @@
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+
+/// What happened in one run over a feed, for anything watching mdmd from outside (e.g., a cron wrapper)
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub feed: String,
+    pub started: String,
+    pub finished: Option<String>,
+    pub last_guid_before: Option<String>,
+    pub last_guid_after: Option<String>,
+    pub considered: usize,
+    pub published: Vec<Published>,
+    pub skipped: Vec<Skipped>,
+    pub stripped: Vec<Stripped>,
+    pub errors: Vec<String>,
+}
+
+/// A post committed to the repository
+#[derive(Debug, Clone, Serialize)]
+pub struct Published {
+    pub filename: String,
+    pub media: usize,
+    // The markdown plus its media
+    pub bytes: u64,
+    pub commit_url: String,
+}
+
+/// An item in the feed which wasn't archived
+#[derive(Debug, Clone, Serialize)]
+pub struct Skipped {
+    pub guid: String,
+    pub reason: String,
+}
+
+/// Metadata removed from a media file before it was published
+#[derive(Debug, Clone, Serialize)]
+pub struct Stripped {
+    pub filename: String,
+    pub media: String,
+    pub removed: Vec<String>,
+}
+
+impl Report {
+    /// A report of a run over `feed`, starting now
+    pub fn new(feed: &str) -> Report {
+        Report {
+            feed: feed.to_owned(),
+            started: now(),
+            ..Report::default()
+        }
+    }
+
+    /// Record that a post was not archived, and why
+    pub fn skip(&mut self, guid: &str, reason: &str) {
+        self.skipped.push(Skipped {
+            guid: guid.to_owned(),
+            reason: reason.to_owned(),
+        });
+    }
+
+    /// Note the end of the run, and how it went
+    pub fn finish<T>(&mut self, result: &Result<T, Box<dyn Error>>) {
+        self.finished = Some(now());
+        if let Err(err) = result {
+            self.errors.push(err.to_string());
+        }
+    }
+
+    /// Add the report as a line of JSON to the end of a file, or print it when the path is `-`
+    pub fn write(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let json = serde_json::to_string(self)?;
+        if path == Path::new("-") {
+            println!("{json}");
+            return Ok(());
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Unable to write {}: {e}", path.display()))?;
+        writeln!(file, "{json}")?;
+        Ok(())
+    }
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_reports_are_appended() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("report.jsonl");
+
+        let mut report = Report::new("https://mastodon.green/@d6y.rss");
+        report.considered = 2;
+        report.published.push(Published {
+            filename: "2023-03-17-toot-110041305366912345.md".to_string(),
+            media: 1,
+            bytes: 1234,
+            commit_url: "https://github.com/d6y/blog/commit/abc".to_string(),
+        });
+        report.skip(
+            "https://mastodon.green/@d6y/110041305366912346",
+            "content warning",
+        );
+        report.finish::<usize>(&Ok(1));
+        report.write(&path).unwrap();
+
+        let mut failed = Report::new("https://mastodon.green/@d6y.rss");
+        failed.finish::<usize>(&Err("feed unavailable".into()));
+        failed.write(&path).unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(2, lines.len());
+        assert_eq!(2, lines[0]["considered"]);
+        assert_eq!(1234, lines[0]["published"][0]["bytes"]);
+        assert_eq!("content warning", lines[0]["skipped"][0]["reason"]);
+        assert_eq!(0, lines[0]["errors"].as_array().unwrap().len());
+        assert_eq!("feed unavailable", lines[1]["errors"][0]);
+    }
+}
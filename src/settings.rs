@@ -0,0 +1,381 @@
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::images::VariantFormat;
+use crate::markdown::{ContentWarnings, ImageMarkup, ReplyContext, SensitiveMedia};
+use crate::media::MediaStorage;
+use crate::media_paths::{MediaLayout, Rule};
+
+/// Everything a run over one feed needs to know. Made with [`Settings::builder`], which checks the settings go together.
+#[derive(Clone)]
+pub struct Settings {
+    pub(crate) feed: String,
+    pub(crate) feed_file: Option<PathBuf>,
+    pub(crate) report: Option<PathBuf>,
+    pub(crate) last_guid_git_path: String,
+    pub(crate) media_path_prefix: String,
+    pub(crate) post_path: String,
+    pub(crate) bundle: bool,
+    pub(crate) instance: String,
+    pub(crate) github_token: String,
+    pub(crate) github_repo: String,
+    pub(crate) github_branch: String,
+    pub(crate) num_posts: usize,
+    pub(crate) media_layout: MediaLayout,
+    // Prefix rules, then regex rules, tried in order
+    pub(crate) media_rules: Vec<Rule>,
+    pub(crate) image_widths: Vec<u32>,
+    pub(crate) image_format: VariantFormat,
+    pub(crate) drop_originals: bool,
+    pub(crate) image_details: bool,
+    pub(crate) strip_metadata: bool,
+    pub(crate) image_markup: ImageMarkup,
+    pub(crate) content_warnings: ContentWarnings,
+    pub(crate) skip_content_warnings: bool,
+    pub(crate) sensitive_media: SensitiveMedia,
+    pub(crate) merge_threads: bool,
+    pub(crate) reply_context: ReplyContext,
+    pub(crate) feed_cache: Option<PathBuf>,
+    pub(crate) media_storage: MediaStorage,
+    pub(crate) s3_endpoint: Option<String>,
+    pub(crate) s3_bucket: Option<String>,
+    pub(crate) s3_region: String,
+    pub(crate) s3_access_key: Option<String>,
+    pub(crate) s3_secret_key: Option<String>,
+    pub(crate) media_base_url: Option<String>,
+}
+
+// Written out, rather than derived, to keep the Github token and S3 secret key out of logs
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Settings")
+            .field("feed", &self.feed)
+            .field("feed_file", &self.feed_file)
+            .field("report", &self.report)
+            .field("last_guid_git_path", &self.last_guid_git_path)
+            .field("media_path_prefix", &self.media_path_prefix)
+            .field("post_path", &self.post_path)
+            .field("bundle", &self.bundle)
+            .field("instance", &self.instance)
+            .field("github_token", &"***")
+            .field("github_repo", &self.github_repo)
+            .field("github_branch", &self.github_branch)
+            .field("num_posts", &self.num_posts)
+            .field("media_layout", &self.media_layout)
+            .field("media_rules", &self.media_rules)
+            .field("image_widths", &self.image_widths)
+            .field("image_format", &self.image_format)
+            .field("drop_originals", &self.drop_originals)
+            .field("image_details", &self.image_details)
+            .field("strip_metadata", &self.strip_metadata)
+            .field("image_markup", &self.image_markup)
+            .field("content_warnings", &self.content_warnings)
+            .field("skip_content_warnings", &self.skip_content_warnings)
+            .field("sensitive_media", &self.sensitive_media)
+            .field("merge_threads", &self.merge_threads)
+            .field("reply_context", &self.reply_context)
+            .field("feed_cache", &self.feed_cache)
+            .field("media_storage", &self.media_storage)
+            .field("s3_endpoint", &self.s3_endpoint)
+            .field("s3_bucket", &self.s3_bucket)
+            .field("s3_region", &self.s3_region)
+            .field("s3_access_key", &self.s3_access_key)
+            .field("s3_secret_key", &self.s3_secret_key.as_ref().map(|_| "***"))
+            .field("media_base_url", &self.media_base_url)
+            .finish()
+    }
+}
+
+impl Settings {
+    /// Settings for archiving to a repository, with the same defaults as the `mdmd` command line
+    pub fn builder(github_token: &str, github_repo: &str) -> SettingsBuilder {
+        let settings = Settings {
+            feed: "http://mastodon.green/@d6y.rss".to_string(),
+            feed_file: None,
+            report: None,
+            last_guid_git_path: "static/mastodon.green/id.txt".to_string(),
+            media_path_prefix: "static".to_string(),
+            post_path: "content/microposts".to_string(),
+            bundle: false,
+            instance: "mastodon.green".to_string(),
+            github_token: github_token.to_owned(),
+            github_repo: github_repo.to_owned(),
+            github_branch: "main".to_string(),
+            num_posts: 1,
+            media_layout: MediaLayout::default(),
+            media_rules: Vec::new(),
+            image_widths: Vec::new(),
+            image_format: VariantFormat::default(),
+            drop_originals: false,
+            image_details: false,
+            strip_metadata: false,
+            image_markup: ImageMarkup::default(),
+            content_warnings: ContentWarnings::default(),
+            skip_content_warnings: false,
+            sensitive_media: SensitiveMedia::default(),
+            merge_threads: false,
+            reply_context: ReplyContext::default(),
+            feed_cache: None,
+            media_storage: MediaStorage::default(),
+            s3_endpoint: None,
+            s3_bucket: None,
+            s3_region: "us-east-1".to_string(),
+            s3_access_key: None,
+            s3_secret_key: None,
+            media_base_url: None,
+        };
+        SettingsBuilder { settings }
+    }
+
+    /// The URL of the feed, which names the run
+    pub fn feed(&self) -> &str {
+        &self.feed
+    }
+
+    /// How media files are named in the repository
+    pub fn media_layout(&self) -> MediaLayout {
+        self.media_layout
+    }
+
+    /// The widths of the resized copies of each image, smallest first
+    pub fn image_widths(&self) -> &[u32] {
+        &self.image_widths
+    }
+
+    /// Combinations of settings which can't work together
+    pub fn check(&self) -> Result<(), String> {
+        if self.media_storage == MediaStorage::S3 {
+            // Bundles keep media next to each post, which a bucket can't do
+            if self.bundle {
+                return Err("--bundle can't be used with --media-storage s3".to_string());
+            }
+            let required = [
+                ("--s3-endpoint", &self.s3_endpoint),
+                ("--s3-bucket", &self.s3_bucket),
+                ("--s3-access-key", &self.s3_access_key),
+                ("--s3-secret-key", &self.s3_secret_key),
+                ("--media-base-url", &self.media_base_url),
+            ];
+            for (name, value) in required {
+                if value.is_none() {
+                    return Err(format!("--media-storage s3 needs {name}"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds [`Settings`] one setting at a time
+#[derive(Debug, Clone)]
+pub struct SettingsBuilder {
+    settings: Settings,
+}
+
+impl SettingsBuilder {
+    /// The URL of the RSS feed to check
+    pub fn feed(mut self, url: &str) -> Self {
+        self.settings.feed = url.to_owned();
+        self
+    }
+
+    /// Read the RSS from a file, or `-` for stdin, rather than fetching the feed
+    pub fn feed_file(mut self, path: &Path) -> Self {
+        self.settings.feed_file = Some(path.to_owned());
+        self
+    }
+
+    /// Append a JSON report of each run to a file, or print it with `-`
+    pub fn report(mut self, path: &Path) -> Self {
+        self.settings.report = Some(path.to_owned());
+        self
+    }
+
+    /// Where the GUID of the last post archived is kept in the repository, with no leading `/`
+    pub fn last_guid_git_path(mut self, path: &str) -> Self {
+        self.settings.last_guid_git_path = path.to_owned();
+        self
+    }
+
+    /// Where media files go in the repository, with no leading `/`
+    pub fn media_path_prefix(mut self, prefix: &str) -> Self {
+        self.settings.media_path_prefix = prefix.to_owned();
+        self
+    }
+
+    /// Where the markdown for posts goes in the repository, with no leading `/`
+    pub fn post_path(mut self, path: &str) -> Self {
+        self.settings.post_path = path.to_owned();
+        self
+    }
+
+    /// Write each post as a Hugo leaf bundle, `<post_path>/<date>-toot-<id>/index.md`, with its media alongside
+    pub fn bundle(mut self, bundle: bool) -> Self {
+        self.settings.bundle = bundle;
+        self
+    }
+
+    /// The instance the feed is from, which goes into the front matter and is used for its API
+    pub fn instance(mut self, instance: &str) -> Self {
+        self.settings.instance = instance.to_owned();
+        self
+    }
+
+    /// The branch to commit to
+    pub fn github_branch(mut self, branch: &str) -> Self {
+        self.settings.github_branch = branch.to_owned();
+        self
+    }
+
+    /// How many posts to archive in a run
+    pub fn num_posts(mut self, num_posts: usize) -> Self {
+        self.settings.num_posts = num_posts;
+        self
+    }
+
+    /// How to name media files in the repository
+    pub fn media_layout(mut self, layout: MediaLayout) -> Self {
+        self.settings.media_layout = layout;
+        self
+    }
+
+    /// Add a rule for turning media URLs into paths, tried after those already added
+    pub fn media_rule(mut self, rule: Rule) -> Self {
+        self.settings.media_rules.push(rule);
+        self
+    }
+
+    /// Widths of resized images, in any order. The largest stands in for the original with `drop_originals`.
+    pub fn image_widths(mut self, widths: &[u32]) -> Self {
+        let mut widths = widths.to_vec();
+        widths.sort_unstable();
+        widths.dedup();
+        self.settings.image_widths = widths;
+        self
+    }
+
+    /// The file format of resized images
+    pub fn image_format(mut self, format: VariantFormat) -> Self {
+        self.settings.image_format = format;
+        self
+    }
+
+    /// Only commit the resized copies of an image, not the original, when it has been resized
+    pub fn drop_originals(mut self, drop_originals: bool) -> Self {
+        self.settings.drop_originals = drop_originals;
+        self
+    }
+
+    /// Measure images, adding width, height and blurhash to the markdown, and an `images:` list to the front matter
+    pub fn image_details(mut self, image_details: bool) -> Self {
+        self.settings.image_details = image_details;
+        self
+    }
+
+    /// Remove EXIF (including GPS) and XMP metadata from images before committing them
+    pub fn strip_metadata(mut self, strip_metadata: bool) -> Self {
+        self.settings.strip_metadata = strip_metadata;
+        self
+    }
+
+    /// How to write images into the markdown
+    pub fn image_markup(mut self, markup: ImageMarkup) -> Self {
+        self.settings.image_markup = markup;
+        self
+    }
+
+    /// How to show posts with a content warning
+    pub fn content_warnings(mut self, content_warnings: ContentWarnings) -> Self {
+        self.settings.content_warnings = content_warnings;
+        self
+    }
+
+    /// Don't archive posts, or threads, with a content warning
+    pub fn skip_content_warnings(mut self, skip: bool) -> Self {
+        self.settings.skip_content_warnings = skip;
+        self
+    }
+
+    /// How to show media marked as sensitive
+    pub fn sensitive_media(mut self, sensitive_media: SensitiveMedia) -> Self {
+        self.settings.sensitive_media = sensitive_media;
+        self
+    }
+
+    /// Write a thread of replies to yourself as one post, rewriting it as the thread grows
+    pub fn merge_threads(mut self, merge_threads: bool) -> Self {
+        self.settings.merge_threads = merge_threads;
+        self
+    }
+
+    /// What to say about the post a reply is to
+    pub fn reply_context(mut self, reply_context: ReplyContext) -> Self {
+        self.settings.reply_context = reply_context;
+        self
+    }
+
+    /// Keep each feed's ETag and Last-Modified in a JSON file, so a sync can stop early when the feed hasn't changed
+    pub fn feed_cache(mut self, path: &Path) -> Self {
+        self.settings.feed_cache = Some(path.to_owned());
+        self
+    }
+
+    /// Where to publish media. S3 storage also needs [`SettingsBuilder::s3`].
+    pub fn media_storage(mut self, storage: MediaStorage) -> Self {
+        self.settings.media_storage = storage;
+        self
+    }
+
+    /// Store media in an S3-compatible bucket, linked from the markdown under `base_url`
+    pub fn s3(
+        mut self,
+        endpoint: &str,
+        bucket: &str,
+        access_key: &str,
+        secret_key: &str,
+        base_url: &str,
+    ) -> Self {
+        self.settings.media_storage = MediaStorage::S3;
+        self.settings.s3_endpoint = Some(endpoint.to_owned());
+        self.settings.s3_bucket = Some(bucket.to_owned());
+        self.settings.s3_access_key = Some(access_key.to_owned());
+        self.settings.s3_secret_key = Some(secret_key.to_owned());
+        self.settings.media_base_url = Some(base_url.to_owned());
+        self
+    }
+
+    /// The region of the S3 bucket, for signing requests. Defaults to `us-east-1`
+    pub fn s3_region(mut self, region: &str) -> Self {
+        self.settings.s3_region = region.to_owned();
+        self
+    }
+
+    /// Check the settings go together, and finish building them
+    pub fn build(self) -> Result<Settings, Box<dyn Error>> {
+        self.settings.check()?;
+        Ok(self.settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_hides_secrets() {
+        let builder = Settings::builder("ghp_token", "d6y/blog").s3(
+            "http://localhost:9000",
+            "media",
+            "key",
+            "s3cret",
+            "https://media.example.org",
+        );
+        let settings = builder.clone().build().unwrap();
+        for debug in [format!("{builder:?}"), format!("{settings:?}")] {
+            assert!(!debug.contains("ghp_token"));
+            assert!(!debug.contains("s3cret"));
+            assert!(debug.contains("d6y/blog"));
+        }
+    }
+}
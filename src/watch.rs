@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::future::IntoFuture;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::extract::State;
+use axum::http::header::{HeaderName, CONTENT_TYPE};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{SecondsFormat, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+use mdmd::archive::Archiver;
+use mdmd::metrics;
+
+use crate::cli::Run;
+use crate::webhook::{self, Webhooks};
+
+/// What the health endpoint reports about the most recent poll
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Health {
+    pub polls: u64,
+    pub last_poll: Option<String>,
+    /// The error from each profile whose last sync failed
+    pub errors: BTreeMap<String, String>,
+}
+
+/// Poll the feed of each profile every `interval` seconds (plus up to `jitter`), until SIGTERM or Ctrl-C.
+/// A signal lets the post being committed finish, but no more are started.
+/// For a profile with a webhook secret, a signed request to the listener syncs it `debounce` seconds later,
+/// without moving the next poll.
+pub async fn watch(
+    runs: &[Run],
+    interval: u64,
+    jitter: u64,
+    listen: Option<SocketAddr>,
+    debounce: u64,
+) -> Result<(), Box<dyn Error>> {
+    let health = Arc::new(Mutex::new(Health::default()));
+    let hooked: Vec<(String, String, String)> = runs
+        .iter()
+        .filter_map(|run| {
+            let secret = run.args.webhook_secret.clone()?;
+            Some((run.name.clone(), run.settings.feed().to_owned(), secret))
+        })
+        .collect();
+    let webhooks = (!hooked.is_empty()).then(|| Arc::new(Webhooks::new(hooked)));
+    if let Some(addr) = listen {
+        let listener = TcpListener::bind(addr).await?;
+        info!("listening on http://{}", listener.local_addr()?);
+        let mut app = router(health.clone());
+        if let Some(webhooks) = &webhooks {
+            app = app.merge(webhook::router(webhooks.clone()));
+        }
+        tokio::spawn(axum::serve(listener, app).into_future());
+    }
+
+    let stopping = Arc::new(AtomicBool::new(false));
+    let stop = Arc::new(Notify::new());
+    {
+        let stopping = stopping.clone();
+        let stop = stop.clone();
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminate.recv() => {},
+                _ = tokio::signal::ctrl_c() => {},
+            }
+            info!("stopping after any post in progress");
+            stopping.store(true, Ordering::SeqCst);
+            stop.notify_one();
+        });
+    }
+
+    // Each profile keeps its archiver, and so its HTTP client, last GUID, and feed ETag, between polls
+    let mut archivers: Vec<(&String, Archiver)> = Vec::new();
+    for run in runs {
+        archivers.push((&run.name, Archiver::new(&run.settings)?));
+    }
+
+    // Which profiles to sync: all of them on a poll, or those a webhook asked for.
+    // Syncs for webhooks don't move the next poll, so every profile is still polled on time.
+    let mut due: Option<BTreeSet<String>> = None;
+    let mut next_poll: Option<Instant> = None;
+    while !stopping.load(Ordering::SeqCst) {
+        if due.is_none() {
+            next_poll = poll_after(interval, jitter);
+        }
+        let mut errors = BTreeMap::new();
+        let mut synced = Vec::new();
+        for (name, archiver) in archivers.iter_mut() {
+            if stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            if due.as_ref().is_some_and(|due| !due.contains(*name)) {
+                continue;
+            }
+            match mdmd::run_with(archiver, &mdmd::Command::Sync, &stopping).await {
+                Ok(outcome) if outcome.changed() == 0 => {}
+                Ok(outcome) => info!("{name}: {} post(s)", outcome.changed()),
+                Err(err) => {
+                    warn!("{name}: {err}");
+                    errors.insert(name.to_string(), err.to_string());
+                }
+            }
+            synced.push(name.to_string());
+        }
+
+        {
+            // Profiles which weren't synced this time keep their last error
+            let mut health = health.lock().unwrap();
+            health.polls += 1;
+            health.last_poll = Some(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+            health.errors.retain(|name, _| !synced.contains(name));
+            health.errors.append(&mut errors);
+        }
+        if let Some(path) = &runs[0].args.metrics_file {
+            if let Err(err) = metrics::write(path) {
+                warn!("{}: {err}", path.display());
+            }
+        }
+
+        // Wait for the next poll or a webhook, skipping any webhook whose profiles have already been synced
+        due = loop {
+            tokio::select! {
+                _ = until(next_poll) => break None,
+                _ = stop.notified() => break None,
+                _ = triggered(webhooks.as_deref()) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs(debounce)) => {},
+                        _ = stop.notified() => break None,
+                    }
+                    let profiles = webhooks.as_ref().map(|w| w.take()).unwrap_or_default();
+                    if !profiles.is_empty() {
+                        break Some(profiles);
+                    }
+                }
+            }
+        };
+    }
+
+    info!("stopped");
+    Ok(())
+}
+
+/// GET /health: 200 when the last sync of each profile worked (or there hasn't been one yet), otherwise 503.
+/// GET /metrics: Prometheus metrics.
+pub fn router(health: Arc<Mutex<Health>>) -> Router {
+    Router::new()
+        .route("/health", get(health_check))
+        .route("/metrics", get(metrics_text))
+        .with_state(health)
+}
+
+async fn metrics_text() -> ([(HeaderName, &'static str); 1], String) {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+}
+
+async fn health_check(State(health): State<Arc<Mutex<Health>>>) -> (StatusCode, Json<Health>) {
+    let health = health.lock().unwrap().clone();
+    let status = match health.errors.is_empty() {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(health))
+}
+
+// When to poll next, which with an interval of zero is never
+fn poll_after(interval: u64, jitter: u64) -> Option<Instant> {
+    match interval {
+        0 => None,
+        _ => Some(Instant::now() + Duration::from_secs(interval + random_up_to(jitter))),
+    }
+}
+
+// Sleep until the next poll, if there is one
+async fn until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn triggered(webhooks: Option<&Webhooks>) {
+    match webhooks {
+        Some(webhooks) => webhooks.triggered.notified().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Good enough randomness for spreading out polls, without another dependency
+fn random_up_to(max: u64) -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    nanos % (max + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_health_endpoint() {
+        let health = Arc::new(Mutex::new(Health::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/health", listener.local_addr().unwrap());
+        tokio::spawn(axum::serve(listener, router(health.clone())).into_future());
+
+        let res = reqwest::get(&url).await.unwrap();
+        assert_eq!(200, res.status().as_u16());
+
+        health
+            .lock()
+            .unwrap()
+            .errors
+            .insert("d6y".to_string(), "feed unavailable".to_string());
+        let res = reqwest::get(&url).await.unwrap();
+        assert_eq!(503, res.status().as_u16());
+        assert!(res.text().await.unwrap().contains("feed unavailable"));
+
+        let metrics_url = url.replace("/health", "/metrics");
+        let res = reqwest::get(&metrics_url).await.unwrap();
+        assert_eq!(200, res.status().as_u16());
+        assert_eq!(
+            Some("text/plain; version=0.0.4"),
+            res.headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+        );
+    }
+
+    #[test]
+    fn test_random_up_to() {
+        assert_eq!(0, random_up_to(0));
+        assert!(random_up_to(5) <= 5);
+    }
+}
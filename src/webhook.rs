@@ -0,0 +1,301 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use axum::Router;
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use log::info;
+use sha1::Sha1;
+use sha2::{Sha256, Sha384, Sha512};
+use tokio::sync::Notify;
+
+/// Requests to sync now, rather than waiting for the next poll: from a WebSub hub, or any webhook
+/// (e.g., a Mastodon "status.created" webhook, or a CI job) which signs its requests with the profile's secret.
+pub struct Webhooks {
+    // Triples of `(profile, feed URL, secret)`, for each profile with a secret
+    feeds: Vec<(String, String, String)>,
+    pending: Mutex<BTreeSet<String>>,
+    pub triggered: Notify,
+}
+
+impl Webhooks {
+    pub fn new(feeds: Vec<(String, String, String)>) -> Webhooks {
+        Webhooks {
+            feeds,
+            pending: Mutex::new(BTreeSet::new()),
+            triggered: Notify::new(),
+        }
+    }
+
+    /// Ask for profiles to be synced
+    pub fn trigger(&self, profiles: Vec<String>) {
+        self.pending.lock().unwrap().extend(profiles);
+        self.triggered.notify_one();
+    }
+
+    /// The profiles asked for since last time
+    pub fn take(&self) -> BTreeSet<String> {
+        std::mem::take(&mut *self.pending.lock().unwrap())
+    }
+}
+
+// Does the request body carry a valid signature, as "<method>=<hex>" in X-Hub-Signature-256 or X-Hub-Signature?
+// WebSub lets hubs sign with sha1, sha256, sha384, or sha512.
+fn signed_by(secret: &str, headers: &HeaderMap, body: &[u8]) -> bool {
+    let signature = ["x-hub-signature-256", "x-hub-signature"]
+        .iter()
+        .find_map(|name| headers.get(*name))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split_once('='))
+        .and_then(|(method, hex)| Some((method, from_hex(hex)?)));
+    let Some((method, signature)) = signature else {
+        return false;
+    };
+    match method {
+        "sha1" => mac_matches::<Hmac<Sha1>>(secret, body, &signature),
+        "sha256" => mac_matches::<Hmac<Sha256>>(secret, body, &signature),
+        "sha384" => mac_matches::<Hmac<Sha384>>(secret, body, &signature),
+        "sha512" => mac_matches::<Hmac<Sha512>>(secret, body, &signature),
+        _ => false,
+    }
+}
+
+fn mac_matches<M: Mac + KeyInit>(secret: &str, body: &[u8], signature: &[u8]) -> bool {
+    let mut mac =
+        <M as Mac>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    mac.verify_slice(signature).is_ok()
+}
+
+/// POST /webhook and /websub sync every profile whose secret signed the request, and POST /webhook/<profile> and /websub/<profile> just the one.
+/// GET /websub answers a hub's check that we want a subscription to the feed.
+pub fn router(webhooks: Arc<Webhooks>) -> Router {
+    Router::new()
+        .route("/webhook", post(push_all))
+        .route("/webhook/:profile", post(push))
+        .route("/websub", post(push_all).get(verify_all))
+        .route("/websub/:profile", post(push).get(verify))
+        .with_state(webhooks)
+}
+
+async fn push_all(
+    State(webhooks): State<Arc<Webhooks>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    accept(&webhooks, None, &headers, &body)
+}
+
+async fn push(
+    State(webhooks): State<Arc<Webhooks>>,
+    Path(profile): Path<String>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    accept(&webhooks, Some(&profile), &headers, &body)
+}
+
+fn accept(
+    webhooks: &Webhooks,
+    profile: Option<&str>,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> StatusCode {
+    let asked_for: Vec<_> = webhooks
+        .feeds
+        .iter()
+        .filter(|(name, _, _)| profile.is_none_or(|p| p == name.as_str()))
+        .collect();
+    if asked_for.is_empty() {
+        return StatusCode::NOT_FOUND;
+    }
+    let signed: Vec<String> = asked_for
+        .into_iter()
+        .filter(|(_, _, secret)| signed_by(secret, headers, body))
+        .map(|(name, _, _)| name.clone())
+        .collect();
+    if signed.is_empty() {
+        return StatusCode::UNAUTHORIZED;
+    }
+    info!("sync requested for {}", signed.join(", "));
+    webhooks.trigger(signed);
+    StatusCode::ACCEPTED
+}
+
+async fn verify_all(
+    State(webhooks): State<Arc<Webhooks>>,
+    Query(query): Query<HashMap<String, String>>,
+) -> (StatusCode, String) {
+    intent(&webhooks, None, &query)
+}
+
+async fn verify(
+    State(webhooks): State<Arc<Webhooks>>,
+    Path(profile): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+) -> (StatusCode, String) {
+    intent(&webhooks, Some(&profile), &query)
+}
+
+// WebSub verification of intent: echo the challenge if the topic is one of our feeds
+fn intent(
+    webhooks: &Webhooks,
+    profile: Option<&str>,
+    query: &HashMap<String, String>,
+) -> (StatusCode, String) {
+    let mode = query.get("hub.mode").map(|m| m.as_str());
+    let topic = query.get("hub.topic");
+    let ours = webhooks
+        .feeds
+        .iter()
+        .any(|(name, feed, _)| profile.is_none_or(|p| p == name.as_str()) && Some(feed) == topic);
+    match (mode, query.get("hub.challenge")) {
+        (Some("subscribe" | "unsubscribe"), Some(challenge)) if ours => {
+            (StatusCode::OK, challenge.to_owned())
+        }
+        _ => (StatusCode::NOT_FOUND, String::new()),
+    }
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::future::IntoFuture;
+    use tokio::net::TcpListener;
+
+    const FEED: &str = "https://mastodon.green/@d6y.rss";
+
+    fn sign(secret: &str, body: &str) -> String {
+        format!("sha256={}", hex_mac::<Hmac<Sha256>>(secret, body))
+    }
+
+    fn hex_mac<M: Mac + KeyInit>(secret: &str, body: &str) -> String {
+        let mut mac = <M as Mac>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    #[test]
+    fn test_signature_methods() {
+        let body = "hello";
+        let signatures = [
+            ("sha1", hex_mac::<Hmac<Sha1>>("s3cret", body)),
+            ("sha256", hex_mac::<Hmac<Sha256>>("s3cret", body)),
+            ("sha384", hex_mac::<Hmac<Sha384>>("s3cret", body)),
+            ("sha512", hex_mac::<Hmac<Sha512>>("s3cret", body)),
+        ];
+        for (method, hex) in &signatures {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                "x-hub-signature",
+                format!("{method}={hex}").parse().unwrap(),
+            );
+            assert!(signed_by("s3cret", &headers, body.as_bytes()), "{method}");
+            assert!(!signed_by("guess", &headers, body.as_bytes()), "{method}");
+        }
+
+        // The method has to match the signature
+        let mut headers = HeaderMap::new();
+        let sha1 = &signatures[0].1;
+        headers.insert("x-hub-signature", format!("sha256={sha1}").parse().unwrap());
+        assert!(!signed_by("s3cret", &headers, body.as_bytes()));
+        headers.insert("x-hub-signature", format!("md5={sha1}").parse().unwrap());
+        assert!(!signed_by("s3cret", &headers, body.as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn test_webhooks() {
+        let webhooks = Arc::new(Webhooks::new(vec![
+            ("d6y".to_string(), FEED.to_string(), "s3cret".to_string()),
+            (
+                "project".to_string(),
+                "https://fosstodon.org/@project.rss".to_string(),
+                "other".to_string(),
+            ),
+        ]));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(axum::serve(listener, router(webhooks.clone())).into_future());
+        let client = reqwest::Client::new();
+
+        let body = r#"{"event":"status.created"}"#;
+        let res = client
+            .post(format!("{base}/webhook/d6y"))
+            .header("X-Hub-Signature", sign("s3cret", body))
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(202, res.status().as_u16());
+        assert_eq!(BTreeSet::from(["d6y".to_string()]), webhooks.take());
+
+        let res = client
+            .post(format!("{base}/webhook/d6y"))
+            .header("X-Hub-Signature", sign("guess", body))
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(401, res.status().as_u16());
+        assert!(webhooks.take().is_empty());
+
+        let res = client
+            .post(format!("{base}/websub/someone"))
+            .header("X-Hub-Signature-256", sign("s3cret", body))
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(404, res.status().as_u16());
+
+        // Only the profiles whose secret signed the request are synced
+        let res = client
+            .post(format!("{base}/webhook"))
+            .header("X-Hub-Signature-256", sign("other", body))
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(202, res.status().as_u16());
+        assert_eq!(BTreeSet::from(["project".to_string()]), webhooks.take());
+
+        let res = client
+            .get(format!("{base}/websub/d6y"))
+            .query(&[
+                ("hub.mode", "subscribe"),
+                ("hub.topic", FEED),
+                ("hub.challenge", "xyz"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(200, res.status().as_u16());
+        assert_eq!("xyz", res.text().await.unwrap());
+    }
+
+    #[test]
+    fn test_from_hex() {
+        assert_eq!(Some(vec![0, 255, 16]), from_hex("00ff10"));
+        assert_eq!(None, from_hex("0"));
+        assert_eq!(None, from_hex("zz"));
+    }
+}
@@ -0,0 +1,85 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use mdmd::archive::Archiver;
+use mdmd::feed::{ChannelSurf, ItemSurf};
+use mdmd::{ContentWarnings, SensitiveMedia, Settings};
+use pretty_assertions::assert_eq;
+use rss::{Channel, Guid};
+
+const TOOT: &str = "https://mastodon.green/@d6y/110041305366912345";
+
+#[test]
+fn test_builder_checks_settings() {
+    let bundle_in_bucket = Settings::builder("token", "d6y/blog")
+        .bundle(true)
+        .s3(
+            "https://s3.example.org",
+            "media",
+            "key",
+            "secret",
+            "https://media.example.org",
+        )
+        .build();
+    assert!(bundle_in_bucket.is_err());
+
+    let bucket_without_details = Settings::builder("token", "d6y/blog")
+        .media_storage(mdmd::MediaStorage::S3)
+        .build();
+    assert!(bucket_without_details.is_err());
+}
+
+#[test]
+fn test_feed_parsing() {
+    let channel = Channel::from_str(include_str!("../rss/example02.rss")).unwrap();
+    let from = Guid {
+        value: "https://mastodon.green/@d6y/110041305366912344".to_string(),
+        permalink: true,
+    };
+
+    let next = channel.find_next_guids(&from);
+    assert_eq!(
+        vec![TOOT],
+        next.iter().map(|g| g.value()).collect::<Vec<_>>()
+    );
+
+    let item = channel.find_by_guid(next[0]).unwrap();
+    assert_eq!(
+        Some("Spoilers for &quot;The Last of Us&quot;".to_string()),
+        item.content_warning()
+    );
+}
+
+// From a feed on disk to markdown, as a sync would commit it, without touching Github
+#[tokio::test]
+async fn test_prepare_post() {
+    let settings = Settings::builder("token", "d6y/blog")
+        .feed_file(Path::new("rss/example02.rss"))
+        .content_warnings(ContentWarnings::Details)
+        .sensitive_media(SensitiveMedia::Reveal)
+        .build()
+        .unwrap();
+    let mut archiver = Archiver::new(&settings).unwrap();
+
+    let rss = archiver.feed(true).await.unwrap().unwrap();
+    let channel = Channel::from_str(&rss).unwrap();
+    let item = channel
+        .items()
+        .iter()
+        .find(|i| i.link() == Some(TOOT))
+        .unwrap();
+
+    let post = archiver.prepare(item).await.unwrap().unwrap();
+    assert_eq!("2023-03-17-toot-110041305366912345.md", post.filename);
+    assert_eq!(
+        "content/microposts/2023-03-17-toot-110041305366912345.md",
+        post.markdown_path
+    );
+    assert!(post
+        .markdown
+        .starts_with("---\ntitle: Fri 17 Mar 2023 18:02\n"));
+    assert!(post
+        .markdown
+        .contains("<details><summary>Spoilers for &quot;The Last of Us&quot;</summary>"));
+    assert_eq!(1, archiver.report().considered);
+}